@@ -0,0 +1,18 @@
+use near_sdk::near;
+
+/// NEP-297 events logged by the contract, workers read these to learn the ids
+/// the contract assigned to new requests and proposals
+#[near(event_json(standard = "veilnet"))]
+pub enum VeilnetEvent {
+    #[event_version("1.0.0")]
+    RequestAdded { request_id: u32 },
+
+    #[event_version("1.0.0")]
+    InferenceRequestAdded { request_id: u32 },
+
+    #[event_version("1.0.0")]
+    ProposalCreated { proposal_id: u32 },
+
+    #[event_version("1.0.0")]
+    ProposalExecuted { proposal_id: u32, approved: bool },
+}
@@ -1,12 +1,14 @@
+pub mod events;
 pub mod state;
 
 use std::collections::{HashMap, HashSet};
 
 // Find all our documentation at https://docs.near.org
+use events::VeilnetEvent;
 use near_sdk::{env, near, require, AccountId, NearToken};
 use state::{
-    GovernanceState, ModelData, ModelStatus, NetworkState, Proposal, ProposalStatus, ProposalType,
-    RequestsState,
+    GovernanceState, InferenceState, ModelData, ModelStatus, ModelType, NetworkState, OldContract,
+    Proposal, ProposalStatus, ProposalType, RequestsState, Vote,
 };
 
 // Define the contract structure
@@ -14,6 +16,7 @@ use state::{
 pub struct Contract {
     pub network: NetworkState,
     pub requests: HashMap<u32, RequestsState>,
+    pub inferences: HashMap<u32, InferenceState>,
     pub current_request_id: u32,
     pub governance: GovernanceState,
 }
@@ -27,6 +30,7 @@ impl Default for Contract {
                 stake: HashMap::new(),
             },
             requests: HashMap::new(),
+            inferences: HashMap::new(),
             current_request_id: 0,
             governance: GovernanceState {
                 proposals: Vec::new(),
@@ -41,6 +45,38 @@ impl Default for Contract {
 // Implement the contract structure
 #[near]
 impl Contract {
+    /// Upgrade the state of a contract deployed before inference requests and model types,
+    /// the requests it already holds were all linear regressions
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldContract =
+            env::state_read().unwrap_or_else(|| env::panic_str("Contract has no state"));
+        let requests = old
+            .requests
+            .into_iter()
+            .map(|(request_id, request)| {
+                let request = RequestsState {
+                    status: request.status,
+                    workers: request.workers,
+                    datasets: request.datasets,
+                    model_cid: request.model_cid,
+                    creator: request.creator,
+                    epochs: request.epochs,
+                    model_type: ModelType::LinearRegression,
+                };
+                (request_id, request)
+            })
+            .collect();
+        Self {
+            network: old.network,
+            requests,
+            inferences: HashMap::new(),
+            current_request_id: old.current_request_id,
+            governance: old.governance,
+        }
+    }
+
     #[payable]
     pub fn add_request(
         &mut self,
@@ -48,7 +84,8 @@ impl Contract {
         dataset_cid: String,
         compressed_sk: Vec<u8>,
         workers: Vec<String>,
-    ) {
+        model_type: ModelType,
+    ) -> u32 {
         let sender = env::predecessor_account_id();
         let fee = env::attached_deposit();
         require!(
@@ -74,21 +111,141 @@ impl Contract {
             model_cid: Vec::new(),
             creator: sender,
             epochs,
+            model_type,
         };
 
-        self.requests.insert(self.current_request_id, request);
+        let request_id = self.current_request_id;
+        self.requests.insert(request_id, request);
         self.current_request_id += 1;
+
+        VeilnetEvent::RequestAdded { request_id }.emit();
+        request_id
     }
 
     pub fn complete_request(&mut self, request_id: u32, model_cid: String) {
-
         let request = self.requests.get_mut(&request_id).unwrap();
         let sender = env::predecessor_account_id();
         let is_worker = request.workers.iter().any(|w| w == &sender);
-        require!(!is_worker, "Only workers can complete requests");
+        require!(is_worker, "Only workers can complete requests");
+        require!(
+            !matches!(request.status, ModelStatus::Cancelled),
+            "Request was cancelled"
+        );
 
-        (*request).status = ModelStatus::Finished;
-        (*request).model_cid.push(model_cid);
+        request.status = ModelStatus::Finished;
+        request.model_cid.push(model_cid);
+    }
+
+    /// Request predictions from a trained model, the id is shared with training requests
+    #[payable]
+    pub fn add_inference_request(
+        &mut self,
+        model_cid: String,
+        input_cid: String,
+        compressed_sk: Vec<u8>,
+        workers: Vec<String>,
+    ) -> u32 {
+        let sender = env::predecessor_account_id();
+        let fee = env::attached_deposit();
+        require!(
+            fee >= NearToken::from_near(self.governance.base_fee * (workers.len() as u128)),
+            "Stake must be greater than staking fee"
+        );
+
+        let workers: HashSet<AccountId> = workers.iter().map(|w| w.parse().unwrap()).collect();
+
+        let inference = InferenceState {
+            status: ModelStatus::Pending,
+            workers,
+            model_cid,
+            input: ModelData {
+                dataset: input_cid,
+                compressed_secret_key: compressed_sk,
+            },
+            predictions_cid: Vec::new(),
+            creator: sender,
+        };
+
+        let request_id = self.current_request_id;
+        self.inferences.insert(request_id, inference);
+        self.current_request_id += 1;
+
+        VeilnetEvent::InferenceRequestAdded { request_id }.emit();
+        request_id
+    }
+
+    pub fn complete_inference_request(&mut self, request_id: u32, predictions_cid: String) {
+        let inference = self
+            .inferences
+            .get_mut(&request_id)
+            .unwrap_or_else(|| env::panic_str("Request does not exist"));
+        let sender = env::predecessor_account_id();
+        let is_worker = inference.workers.iter().any(|w| w == &sender);
+        require!(is_worker, "Only workers can complete requests");
+        require!(
+            !matches!(inference.status, ModelStatus::Cancelled),
+            "Request was cancelled"
+        );
+        require!(
+            !matches!(inference.status, ModelStatus::Finished),
+            "Request is already finished"
+        );
+
+        inference.status = ModelStatus::Finished;
+        inference.predictions_cid.push(predictions_cid);
+    }
+
+    /// Withdraw a training or inference request that no worker has completed yet
+    pub fn cancel_request(&mut self, request_id: u32) {
+        let sender = env::predecessor_account_id();
+        let (creator, status) = if let Some(request) = self.requests.get_mut(&request_id) {
+            (&request.creator, &mut request.status)
+        } else if let Some(inference) = self.inferences.get_mut(&request_id) {
+            (&inference.creator, &mut inference.status)
+        } else {
+            env::panic_str("Request does not exist");
+        };
+        require!(creator == &sender, "Only the creator can cancel a request");
+        require!(
+            matches!(status, ModelStatus::Pending | ModelStatus::Training),
+            "Request is already closed"
+        );
+        //TODO: refund the fee of the workers that did not complete the request
+        *status = ModelStatus::Cancelled;
+    }
+
+    /// Training requests assigned to `worker` that are not finished yet, keyed by request id
+    pub fn get_open_requests(&self, worker: AccountId) -> Vec<(u32, RequestsState)> {
+        self.requests
+            .iter()
+            .filter(|(_, r)| {
+                matches!(r.status, ModelStatus::Pending | ModelStatus::Training)
+                    && r.workers.contains(&worker)
+            })
+            .map(|(id, r)| (*id, r.clone()))
+            .collect()
+    }
+
+    /// The training request with this id, including the models submitted for it
+    pub fn get_request(&self, request_id: u32) -> Option<RequestsState> {
+        self.requests.get(&request_id).cloned()
+    }
+
+    /// The inference request with this id, including the predictions submitted for it
+    pub fn get_inference_request(&self, request_id: u32) -> Option<InferenceState> {
+        self.inferences.get(&request_id).cloned()
+    }
+
+    /// Inference requests assigned to `worker` that are not finished yet, keyed by request id
+    pub fn get_open_inference_requests(&self, worker: AccountId) -> Vec<(u32, InferenceState)> {
+        self.inferences
+            .iter()
+            .filter(|(_, r)| {
+                matches!(r.status, ModelStatus::Pending | ModelStatus::Training)
+                    && r.workers.contains(&worker)
+            })
+            .map(|(id, r)| (*id, r.clone()))
+            .collect()
     }
 
     #[payable]
@@ -106,18 +263,51 @@ impl Contract {
             worker,
         );
 
-        self.governance.proposals.push(proposal);
+        self.push_proposal(proposal);
+    }
+
+    /// Vote on a pending proposal, every worker votes at most once
+    pub fn vote(&mut self, proposal_id: u32, vote: Vote) {
+        let sender = env::predecessor_account_id();
+        require!(
+            self.network.workers.contains(&sender),
+            "Only workers can vote"
+        );
+
+        let proposal = self
+            .governance
+            .proposals
+            .get_mut(proposal_id as usize)
+            .unwrap_or_else(|| env::panic_str("Proposal does not exist"));
+        require!(
+            matches!(proposal.status, ProposalStatus::Pending),
+            "Proposal is already executed"
+        );
+        require!(
+            !proposal.votes.contains_key(&sender),
+            "Worker already voted on this proposal"
+        );
+
+        match vote {
+            Vote::For => proposal.for_votes += 1,
+            Vote::Against => proposal.angaist_votes += 1,
+        }
+        proposal.votes.insert(sender, vote);
     }
 
-    pub fn execute_proposal(&mut self, proposal_id: usize) {
+    pub fn execute_proposal(&mut self, proposal_id: u32) {
         let sender = env::predecessor_account_id();
         require!(
             sender == self.governance.admin,
             "Only admin can execute proposals"
         );
 
-        let proposal = self.governance.proposals.get_mut(proposal_id).unwrap();
-        let accepted = (*proposal).for_votes > (*proposal).angaist_votes;
+        let proposal = self
+            .governance
+            .proposals
+            .get_mut(proposal_id as usize)
+            .unwrap_or_else(|| env::panic_str("Proposal does not exist"));
+        let accepted = proposal.for_votes > proposal.angaist_votes;
 
         if accepted {
             match &proposal.proposal_type {
@@ -137,10 +327,16 @@ impl Contract {
                     self.governance.staking_fee = *stake;
                 }
             }
-            (*proposal).status = ProposalStatus::Approved;
+            proposal.status = ProposalStatus::Approved;
         } else {
-            (*proposal).status = ProposalStatus::Rejected;
+            proposal.status = ProposalStatus::Rejected;
+        }
+
+        VeilnetEvent::ProposalExecuted {
+            proposal_id,
+            approved: accepted,
         }
+        .emit();
     }
 
     pub fn propose_remove_worker(&mut self, worker: String) {
@@ -150,7 +346,7 @@ impl Contract {
             ProposalType::RemoveWorker(worker.parse().unwrap()),
             sender,
         );
-        self.governance.proposals.push(proposal);
+        self.push_proposal(proposal);
     }
 
     pub fn propose_change_base_fee(&mut self, fee: u128) {
@@ -160,7 +356,7 @@ impl Contract {
             ProposalType::ChangeBaseFee(fee),
             sender,
         );
-        self.governance.proposals.push(proposal);
+        self.push_proposal(proposal);
     }
 
     pub fn propose_change_stake_amount(&mut self, stake: u128) {
@@ -170,13 +366,253 @@ impl Contract {
             ProposalType::ChangeStakeAmount(stake),
             sender,
         );
-        self.governance.proposals.push(proposal);
+        self.push_proposal(proposal);
     }
 
     //TODO: Implement the krum function verification
     pub fn verify_krum_and_slash() {}
 }
 
+impl Contract {
+    fn push_proposal(&mut self, proposal: Proposal) {
+        let proposal_id = self.governance.proposals.len() as u32;
+        self.governance.proposals.push(proposal);
+        VeilnetEvent::ProposalCreated { proposal_id }.emit();
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    fn call_as(account_id: &str) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account(account_id))
+            .build());
+    }
+
+    /// An empty contract administered by `admin.near`
+    fn contract() -> Contract {
+        Contract {
+            network: NetworkState {
+                workers: HashSet::new(),
+                stake: HashMap::new(),
+            },
+            requests: HashMap::new(),
+            inferences: HashMap::new(),
+            current_request_id: 0,
+            governance: GovernanceState {
+                proposals: Vec::new(),
+                staking_fee: 0,
+                admin: account("admin.near"),
+                base_fee: 0,
+            },
+        }
+    }
+
+    /// A contract with the workers `a.near` and `b.near`, and a proposal to add `c.near`
+    fn governed_contract() -> Contract {
+        let mut contract = contract();
+        contract.network.workers.insert(account("a.near"));
+        contract.network.workers.insert(account("b.near"));
+        call_as("c.near");
+        contract.add_worker();
+        contract
+    }
+
+    /// A contract with training request 0 and inference request 1, both created by `alice.near`
+    fn contract_with_requests() -> Contract {
+        let mut contract = contract();
+        call_as("alice.near");
+        contract.add_request(
+            1,
+            "dataset".to_string(),
+            vec![1],
+            vec!["a.near".to_string()],
+            ModelType::LinearRegression,
+        );
+        contract.add_inference_request(
+            "model".to_string(),
+            "input".to_string(),
+            vec![2],
+            vec!["a.near".to_string()],
+        );
+        contract
+    }
+
+    #[test]
+    fn workers_vote_once_per_proposal() {
+        let mut contract = governed_contract();
+        call_as("a.near");
+        contract.vote(0, Vote::For);
+        call_as("b.near");
+        contract.vote(0, Vote::Against);
+
+        let proposal = &contract.governance.proposals[0];
+        assert_eq!((proposal.for_votes, proposal.angaist_votes), (1, 1));
+        assert_eq!(proposal.votes.len(), 2);
+        assert!(matches!(proposal.votes[&account("b.near")], Vote::Against));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only workers can vote")]
+    fn only_workers_vote() {
+        let mut contract = governed_contract();
+        call_as("c.near");
+        contract.vote(0, Vote::For);
+    }
+
+    #[test]
+    #[should_panic(expected = "Worker already voted on this proposal")]
+    fn workers_can_not_vote_twice() {
+        let mut contract = governed_contract();
+        call_as("a.near");
+        contract.vote(0, Vote::For);
+        contract.vote(0, Vote::Against);
+    }
+
+    #[test]
+    #[should_panic(expected = "Proposal is already executed")]
+    fn executed_proposals_take_no_votes() {
+        let mut contract = governed_contract();
+        call_as("a.near");
+        contract.vote(0, Vote::For);
+        call_as(contract.governance.admin.clone().as_str());
+        contract.execute_proposal(0);
+        assert!(contract.network.workers.contains(&account("c.near")));
+
+        call_as("b.near");
+        contract.vote(0, Vote::Against);
+    }
+
+    #[test]
+    fn creators_cancel_open_requests() {
+        let mut contract = contract_with_requests();
+        call_as("alice.near");
+        contract.cancel_request(0);
+        contract.cancel_request(1);
+        assert!(matches!(
+            contract.requests[&0].status,
+            ModelStatus::Cancelled
+        ));
+        assert!(matches!(
+            contract.inferences[&1].status,
+            ModelStatus::Cancelled
+        ));
+        assert!(contract.get_open_requests(account("a.near")).is_empty());
+        assert!(contract
+            .get_open_inference_requests(account("a.near"))
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the creator can cancel a request")]
+    fn only_the_creator_cancels() {
+        let mut contract = contract_with_requests();
+        call_as("a.near");
+        contract.cancel_request(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Request is already closed")]
+    fn finished_requests_can_not_be_cancelled() {
+        let mut contract = contract_with_requests();
+        call_as("a.near");
+        contract.complete_inference_request(1, "predictions".to_string());
+        call_as("alice.near");
+        contract.cancel_request(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Request does not exist")]
+    fn unknown_requests_can_not_be_cancelled() {
+        let mut contract = contract_with_requests();
+        call_as("alice.near");
+        contract.cancel_request(2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Request was cancelled")]
+    fn cancelled_requests_can_not_be_completed() {
+        let mut contract = contract_with_requests();
+        call_as("alice.near");
+        contract.cancel_request(0);
+        call_as("a.near");
+        contract.complete_request(0, "model".to_string());
+    }
+
+    #[test]
+    fn completed_requests_keep_their_results() {
+        let mut contract = contract_with_requests();
+        call_as("a.near");
+        contract.complete_request(0, "model".to_string());
+        contract.complete_inference_request(1, "predictions".to_string());
+
+        let request = contract.get_request(0).unwrap();
+        assert!(matches!(request.status, ModelStatus::Finished));
+        assert_eq!(request.model_cid, vec!["model".to_string()]);
+        let inference = contract.get_inference_request(1).unwrap();
+        assert_eq!(inference.predictions_cid, vec!["predictions".to_string()]);
+        assert!(contract.get_request(1).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Proposal does not exist")]
+    fn unknown_proposals_can_not_be_executed() {
+        let mut contract = governed_contract();
+        call_as("admin.near");
+        contract.execute_proposal(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Request is already finished")]
+    fn finished_inference_requests_can_not_be_completed_again() {
+        let mut contract = contract_with_requests();
+        call_as("a.near");
+        contract.complete_inference_request(1, "predictions".to_string());
+        contract.complete_inference_request(1, "predictions".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Request does not exist")]
+    fn unknown_inference_requests_can_not_be_completed() {
+        let mut contract = contract_with_requests();
+        call_as("a.near");
+        contract.complete_inference_request(0, "predictions".to_string());
+    }
+
+    #[test]
+    fn migrate_reads_the_state_without_inferences() {
+        call_as("veilnet.near");
+        let request = state::OldRequestsState {
+            status: ModelStatus::Finished,
+            workers: HashSet::from([account("a.near")]),
+            datasets: HashMap::new(),
+            model_cid: vec!["model".to_string()],
+            creator: account("alice.near"),
+            epochs: 3,
+        };
+        let old = OldContract {
+            network: contract().network,
+            requests: HashMap::from([(4, request)]),
+            current_request_id: 5,
+            governance: contract().governance,
+        };
+        env::state_write(&old);
+
+        let contract = Contract::migrate();
+        assert_eq!(contract.current_request_id, 5);
+        assert_eq!(contract.governance.admin, account("admin.near"));
+        assert!(contract.inferences.is_empty());
+        let request = &contract.requests[&4];
+        assert_eq!(request.model_type, ModelType::LinearRegression);
+        assert_eq!(request.model_cid, vec!["model".to_string()]);
+        assert_eq!(request.epochs, 3);
+    }
 }
@@ -19,6 +19,39 @@ pub struct RequestsState {
     pub model_cid: Vec<String>,
     pub creator: AccountId,
     pub epochs: u32,
+    pub model_type: ModelType,
+}
+
+/// The contract state as deployed before inference requests and model types, read by `migrate`
+#[near(serializers = [borsh])]
+pub struct OldContract {
+    pub network: NetworkState,
+    pub requests: HashMap<u32, OldRequestsState>,
+    pub current_request_id: u32,
+    pub governance: GovernanceState,
+}
+
+/// A training request as stored before it had a model type
+#[near(serializers = [borsh])]
+pub struct OldRequestsState {
+    pub status: ModelStatus,
+    pub workers: HashSet<AccountId>,
+    pub datasets: HashMap<AccountId, ModelData>,
+    pub model_cid: Vec<String>,
+    pub creator: AccountId,
+    pub epochs: u32,
+}
+
+/// Represents an inference request i.e. encrypted rows to evaluate with an already trained model
+#[near(serializers = [json,borsh])]
+#[derive(Clone)]
+pub struct InferenceState {
+    pub status: ModelStatus,
+    pub workers: HashSet<AccountId>,
+    pub model_cid: String, // cid of the trained model parameters
+    pub input: ModelData,  // the encrypted feature rows and the client's key
+    pub predictions_cid: Vec<String>,
+    pub creator: AccountId,
 }
 
 #[near(serializers = [json,borsh])]
@@ -28,12 +61,22 @@ pub struct ModelData {
     pub compressed_secret_key: Vec<u8>, // the compressed serialized secret for the client
 }
 
+/// The model family the workers should train on the encrypted dataset
+#[near(serializers = [json,borsh])]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ModelType {
+    LinearRegression,
+    LogisticRegression, // sigmoid replaced by a polynomial approximation
+    Perceptron,
+}
+
 #[near(serializers = [json,borsh])]
 #[derive(Clone)]
 pub enum ModelStatus {
     Pending, // The pending state is that it is waiting for the workers to join
     Training,
     Finished,
+    Cancelled, // withdrawn by the creator before any worker completed it
 }
 
 // governance structs for adding workers and removing workers
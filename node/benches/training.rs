@@ -0,0 +1,110 @@
+//! Compares single threaded and parallel encrypted training on a small synthetic dataset.
+//!
+//! Run with `cargo bench --bench training`.
+use std::fs::File;
+use std::thread::available_parallelism;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use node::dataset::{Column, ColumnType, DatasetHeader, DatasetWriter, FheParameters};
+use node::model::{run_training, ModelType, Options};
+use tfhe::prelude::*;
+use tfhe::safe_serialization::safe_serialize;
+use tfhe::{ClientKey, CompressedServerKey, FheUint8};
+
+const FEATURES: usize = 4;
+const ROWS: u64 = 8;
+const SERIALIZED_SIZE_LIMIT: u64 = 1 << 30;
+
+/// Encrypt a deterministic dataset and return its path with the compressed server key
+fn synthetic_dataset() -> (std::path::PathBuf, Vec<u8>) {
+    let parameters = FheParameters::Message2Carry2KsPbs;
+    let client_key = ClientKey::generate(parameters.config());
+
+    let mut compressed_server_key = Vec::new();
+    safe_serialize(
+        &CompressedServerKey::new(&client_key),
+        &mut compressed_server_key,
+        SERIALIZED_SIZE_LIMIT,
+    )
+    .unwrap();
+
+    let mut columns: Vec<Column> = (0..FEATURES)
+        .map(|i| Column {
+            name: format!("x{i}"),
+            column_type: ColumnType::FheUint8,
+        })
+        .collect();
+    columns.push(Column {
+        name: "y".to_string(),
+        column_type: ColumnType::FheUint8,
+    });
+
+    let path = std::env::temp_dir().join("veilnet-training-bench.vnfl");
+    let mut writer = DatasetWriter::new(
+        File::create(&path).unwrap(),
+        DatasetHeader {
+            parameters,
+            columns,
+            row_count: ROWS,
+            label_column: Some(FEATURES as u32),
+        },
+    )
+    .unwrap();
+    for row in 0..ROWS {
+        let cells: Vec<Vec<u8>> = (0..=FEATURES)
+            .map(|col| {
+                let value = ((row as usize * 7 + col * 3) % 4) as u8;
+                let mut buffer = Vec::new();
+                safe_serialize(
+                    &FheUint8::encrypt(value, &client_key),
+                    &mut buffer,
+                    SERIALIZED_SIZE_LIMIT,
+                )
+                .unwrap();
+                buffer
+            })
+            .collect();
+        writer.write_row(&cells).unwrap();
+    }
+    writer.finish().unwrap();
+
+    (path, compressed_server_key)
+}
+
+fn bench_training(c: &mut Criterion) {
+    let (path, compressed_server_key) = synthetic_dataset();
+    let cores = available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+    let mut group = c.benchmark_group("linear_regression_epoch");
+    group.sample_size(10);
+    // a single core machine only has the single threaded case
+    let mut thread_counts = vec![1, cores];
+    thread_counts.dedup();
+    for threads in thread_counts {
+        let options = Options {
+            batch_size: ROWS as usize,
+            threads,
+        };
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &options,
+            |b, options| {
+                b.iter(|| {
+                    let mut file = File::open(&path).unwrap();
+                    run_training(
+                        &mut file,
+                        &compressed_server_key,
+                        1,
+                        ModelType::LinearRegression,
+                        options,
+                    )
+                    .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_training);
+criterion_main!(benches);
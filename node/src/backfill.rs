@@ -0,0 +1,257 @@
+//! Startup reconciliation against the contract state.
+//!
+//! The indexer only sees requests that are added while it is running and within the lake's
+//! retention, so before it starts the worker asks the contract for every open request it is
+//! assigned to. Jobs are keyed by request id, anything the indexer delivers later is ignored.
+//! The open requests are recorded in the mirror as well, so the indexer can track them being
+//! completed or cancelled. Queued jobs of requests that are not open anymore were closed while
+//! the worker was offline, they are cancelled.
+use crate::config::Config;
+use crate::mirror::ContractMirror;
+use crate::queue::{JobState, RequestQueue};
+use crate::types::{InferenceRequest, ModelData, TrainingRequest};
+
+use std::collections::HashSet;
+
+use anyhow::Context;
+use contract::state::{InferenceState, RequestsState};
+use near_lake_primitives::AccountId;
+use serde_json::json;
+
+/// Merge the open requests assigned to this worker into the queue and the mirror,
+/// returns how many jobs were new
+pub async fn run(
+    rpc_client: &near_fetch::Client,
+    config: &Config,
+    queue: &RequestQueue,
+    mirror: &ContractMirror,
+) -> anyhow::Result<usize> {
+    let args = json!({ "worker": config.account_id });
+    let requests: Vec<(u32, RequestsState)> = rpc_client
+        .view(&config.contract_id, "get_open_requests")
+        .args_json(args.clone())
+        .await
+        .context("failed to fetch open requests")?
+        .json()?;
+    let inferences: Vec<(u32, InferenceState)> = rpc_client
+        .view(&config.contract_id, "get_open_inference_requests")
+        .args_json(args)
+        .await
+        .context("failed to fetch open inference requests")?
+        .json()?;
+    tracing::info!(
+        requests = requests.len(),
+        inferences = inferences.len(),
+        "fetched open requests from the contract"
+    );
+    merge(requests, inferences, queue, mirror).await
+}
+
+/// Merge the open requests of the view calls into the queue and the mirror, and cancel the
+/// queued jobs of requests that were closed since they were queued. Returns how many jobs
+/// were new.
+async fn merge(
+    requests: Vec<(u32, RequestsState)>,
+    inferences: Vec<(u32, InferenceState)>,
+    queue: &RequestQueue,
+    mirror: &ContractMirror,
+) -> anyhow::Result<usize> {
+    let open: HashSet<u32> = requests
+        .iter()
+        .map(|(id, _)| *id)
+        .chain(inferences.iter().map(|(id, _)| *id))
+        .collect();
+    for id in queue.queued()? {
+        if !open.contains(&id) {
+            tracing::info!(id, "request was closed while the worker was offline");
+            queue.set_state(id, JobState::Cancelled)?;
+        }
+    }
+
+    let mut added = 0;
+    for (request_id, request) in requests {
+        let workers = parse_workers(request.workers.iter())?;
+        mirror.add_request(request_id, workers.clone()).await;
+        let Some(data) = request.datasets.get(&request.creator) else {
+            tracing::warn!(request_id, "open request has no dataset from its creator");
+            continue;
+        };
+        let request = TrainingRequest {
+            request_id,
+            epochs: request.epochs,
+            model_type: request.model_type,
+            creator: parse_account(&request.creator)?,
+            workers,
+            data: ModelData {
+                dataset: data.dataset.clone(),
+                compressed_secret_key: data.compressed_secret_key.clone(),
+            },
+        };
+        added += usize::from(queue.add_request(request)?);
+    }
+    for (request_id, inference) in inferences {
+        let workers = parse_workers(inference.workers.iter())?;
+        mirror.add_request(request_id, workers.clone()).await;
+        let inference = InferenceRequest {
+            request_id,
+            creator: parse_account(&inference.creator)?,
+            workers,
+            model: inference.model_cid,
+            input: ModelData {
+                dataset: inference.input.dataset,
+                compressed_secret_key: inference.input.compressed_secret_key,
+            },
+        };
+        added += usize::from(queue.add_inference(inference)?);
+    }
+    Ok(added)
+}
+
+/// The contract and the indexer depend on different near crates, convert through the string form
+fn parse_account(account_id: &impl AsRef<str>) -> anyhow::Result<AccountId> {
+    let account_id = account_id.as_ref();
+    account_id
+        .parse()
+        .with_context(|| format!("invalid account id {account_id}"))
+}
+
+fn parse_workers<'a, T: AsRef<str> + 'a>(
+    workers: impl Iterator<Item = &'a T>,
+) -> anyhow::Result<Vec<AccountId>> {
+    workers.map(parse_account).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mirror::RequestStatus;
+    use crate::model::ModelType;
+    use crate::queue::JobKind;
+
+    fn queue() -> RequestQueue {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        RequestQueue::open(&db).unwrap()
+    }
+
+    /// `get_open_requests` as the contract answers it
+    fn open_requests(ids: &[u32]) -> Vec<(u32, RequestsState)> {
+        let requests: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                json!([id, {
+                    "status": "Pending",
+                    "workers": ["worker.test.near", "other.test.near"],
+                    "datasets": {
+                        "alice.test.near": {
+                            "dataset": format!("dataset-{id}"),
+                            "compressed_secret_key": [1, 2, 3],
+                        },
+                    },
+                    "model_cid": [],
+                    "creator": "alice.test.near",
+                    "epochs": 2,
+                    "model_type": "Perceptron",
+                }])
+            })
+            .collect();
+        serde_json::from_value(json!(requests)).unwrap()
+    }
+
+    /// `get_open_inference_requests` as the contract answers it
+    fn open_inferences(ids: &[u32]) -> Vec<(u32, InferenceState)> {
+        let inferences: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                json!([id, {
+                    "status": "Training",
+                    "workers": ["worker.test.near"],
+                    "model_cid": "model",
+                    "input": {"dataset": "input", "compressed_secret_key": [4, 5, 6]},
+                    "predictions_cid": [],
+                    "creator": "alice.test.near",
+                }])
+            })
+            .collect();
+        serde_json::from_value(json!(inferences)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn queues_open_requests_and_records_them_in_the_mirror() {
+        let (queue, mirror) = (queue(), ContractMirror::default());
+        let added = merge(open_requests(&[1]), open_inferences(&[2]), &queue, &mirror)
+            .await
+            .unwrap();
+        assert_eq!(added, 2);
+
+        let JobKind::Training(request) = queue.get(1).unwrap().unwrap().kind else {
+            panic!("request 1 is a training request");
+        };
+        assert_eq!(request.epochs, 2);
+        assert_eq!(request.model_type, ModelType::Perceptron);
+        assert_eq!(request.data.dataset, "dataset-1");
+        assert_eq!(request.data.compressed_secret_key, vec![1, 2, 3]);
+        let JobKind::Inference(inference) = queue.get(2).unwrap().unwrap().kind else {
+            panic!("request 2 is an inference request");
+        };
+        assert_eq!(inference.model, "model");
+        assert_eq!(inference.input.compressed_secret_key, vec![4, 5, 6]);
+
+        let request = mirror.request(1).await.unwrap();
+        assert_eq!(request.status, RequestStatus::Open);
+        assert_eq!(request.workers.len(), 2);
+        assert!(mirror.request(2).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn skips_requests_that_are_queued_already() {
+        let (queue, mirror) = (queue(), ContractMirror::default());
+        merge(open_requests(&[1, 2]), Vec::new(), &queue, &mirror)
+            .await
+            .unwrap();
+        queue.set_state(1, JobState::Training).unwrap();
+
+        let added = merge(open_requests(&[1, 2, 3]), Vec::new(), &queue, &mirror)
+            .await
+            .unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(queue.get(1).unwrap().unwrap().state, JobState::Training);
+        assert_eq!(queue.queued().unwrap(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn cancels_queued_jobs_of_requests_closed_in_the_meantime() {
+        let (queue, mirror) = (queue(), ContractMirror::default());
+        merge(
+            open_requests(&[1, 2, 3]),
+            open_inferences(&[4]),
+            &queue,
+            &mirror,
+        )
+        .await
+        .unwrap();
+        queue.set_state(2, JobState::Training).unwrap();
+
+        // requests 1, 2 and 4 were completed or cancelled while the worker was offline
+        let added = merge(open_requests(&[3]), Vec::new(), &queue, &mirror)
+            .await
+            .unwrap();
+        assert_eq!(added, 0);
+        assert_eq!(queue.get(1).unwrap().unwrap().state, JobState::Cancelled);
+        assert_eq!(queue.get(4).unwrap().unwrap().state, JobState::Cancelled);
+        // started jobs are resumed, the worker finds out whether they are still wanted
+        assert_eq!(queue.get(2).unwrap().unwrap().state, JobState::Training);
+        assert_eq!(queue.queued().unwrap(), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn skips_requests_without_a_dataset_from_their_creator() {
+        let (queue, mirror) = (queue(), ContractMirror::default());
+        let mut requests = open_requests(&[1]);
+        requests[0].1.datasets.clear();
+        let added = merge(requests, Vec::new(), &queue, &mirror).await.unwrap();
+        assert_eq!(added, 0);
+        assert!(queue.get(1).unwrap().is_none());
+        // still recorded, the indexer tracks it being closed
+        assert!(mirror.request(1).await.is_some());
+    }
+}
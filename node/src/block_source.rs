@@ -0,0 +1,250 @@
+//! Where the indexer reads blocks from.
+//!
+//! NEAR Lake pushes blocks into `handle_block` itself, the sources in this module are pulled by
+//! the indexer instead. Every source yields the same `StreamerMessage` the lake produces, so
+//! `handle_block` cannot tell them apart.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use near_lake_framework::near_indexer_primitives::{
+    types::BlockHeight, views, IndexerChunkView, IndexerExecutionOutcomeWithOptionalReceipt,
+    IndexerExecutionOutcomeWithReceipt, IndexerShard, IndexerTransactionWithOutcome,
+    StreamerMessage,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockSourceKind {
+    /// NEAR Lake on AWS S3 for the configured chain
+    Lake,
+    /// Poll the JSON-RPC of any node, e.g. a local near-sandbox
+    Rpc,
+    /// Replay recorded blocks from a directory
+    Directory,
+}
+
+/// A source the indexer pulls blocks from, one at a time and in height order
+pub trait BlockSource: Send {
+    /// The block after the previously returned one, `None` once the source is exhausted
+    fn next_block(
+        &mut self,
+    ) -> impl Future<Output = anyhow::Result<Option<StreamerMessage>>> + Send;
+}
+
+/// Polls a JSON-RPC node for final blocks.
+///
+/// Receipt outcomes are only fetched for transactions sent to the contract and are attached to
+/// the block that included the transaction, which is all `handle_block` looks at.
+pub struct RpcBlockSource {
+    http: reqwest::Client,
+    url: String,
+    contract_id: String,
+    next_height: BlockHeight,
+    final_height: BlockHeight,
+    poll_interval: Duration,
+}
+
+impl RpcBlockSource {
+    pub fn new(
+        url: &str,
+        contract_id: &str,
+        start_block_height: BlockHeight,
+        poll_interval: Duration,
+    ) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            url: url.to_string(),
+            contract_id: contract_id.to_string(),
+            next_height: start_block_height,
+            final_height: 0,
+            poll_interval,
+        }
+    }
+
+    /// Call a JSON-RPC method, `None` if the node does not know the requested block or chunk
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> anyhow::Result<Option<T>> {
+        let response: Value = self
+            .http
+            .post(&self.url)
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": "veilnet",
+                "method": method,
+                "params": params,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if let Some(error) = response.get("error") {
+            let cause = &error["cause"]["name"];
+            if cause == "UNKNOWN_BLOCK" || cause == "UNKNOWN_CHUNK" {
+                return Ok(None);
+            }
+            anyhow::bail!("{method} failed: {error}");
+        }
+        let result = response
+            .get("result")
+            .cloned()
+            .with_context(|| format!("{method} response has neither a result nor an error"))?;
+        let result = serde_json::from_value(result)
+            .with_context(|| format!("unexpected {method} response"))?;
+        Ok(Some(result))
+    }
+
+    async fn streamer_message(&self, block: views::BlockView) -> anyhow::Result<StreamerMessage> {
+        let mut shards = Vec::with_capacity(block.chunks.len());
+        for header in &block.chunks {
+            let mut shard = IndexerShard {
+                shard_id: header.shard_id,
+                chunk: None,
+                receipt_execution_outcomes: Vec::new(),
+                state_changes: Vec::new(),
+            };
+            // chunks that were not produced in this block are repeated from an earlier one
+            if header.height_included != block.header.height {
+                shards.push(shard);
+                continue;
+            }
+
+            let chunk: views::ChunkView = self
+                .call("chunk", json!({ "chunk_id": header.chunk_hash }))
+                .await?
+                .context("chunk of a final block is missing")?;
+            let mut transactions = Vec::new();
+            for transaction in chunk.transactions {
+                if transaction.receiver_id.as_str() != self.contract_id {
+                    continue;
+                }
+                let status: views::FinalExecutionOutcomeWithReceiptView = self
+                    .call(
+                        "EXPERIMENTAL_tx_status",
+                        json!({
+                            "tx_hash": transaction.hash,
+                            "sender_account_id": transaction.signer_id,
+                            "wait_until": "FINAL",
+                        }),
+                    )
+                    .await?
+                    .context("status of an included transaction is missing")?;
+
+                for outcome in status.final_outcome.receipts_outcome {
+                    let receipt = status
+                        .receipts
+                        .iter()
+                        .find(|receipt| receipt.receipt_id == outcome.id);
+                    if let Some(receipt) = receipt {
+                        shard
+                            .receipt_execution_outcomes
+                            .push(IndexerExecutionOutcomeWithReceipt {
+                                execution_outcome: outcome,
+                                receipt: receipt.clone(),
+                            });
+                    }
+                }
+                transactions.push(IndexerTransactionWithOutcome {
+                    transaction,
+                    outcome: IndexerExecutionOutcomeWithOptionalReceipt {
+                        execution_outcome: status.final_outcome.transaction_outcome,
+                        receipt: None,
+                    },
+                });
+            }
+            shard.chunk = Some(IndexerChunkView {
+                author: chunk.author,
+                header: chunk.header,
+                transactions,
+                receipts: chunk.receipts,
+            });
+            shards.push(shard);
+        }
+        Ok(StreamerMessage { block, shards })
+    }
+}
+
+impl BlockSource for RpcBlockSource {
+    async fn next_block(&mut self) -> anyhow::Result<Option<StreamerMessage>> {
+        loop {
+            if self.next_height > self.final_height {
+                let head: views::BlockView = self
+                    .call("block", json!({ "finality": "final" }))
+                    .await?
+                    .context("node has no final block")?;
+                self.final_height = head.header.height;
+                if self.next_height > self.final_height {
+                    tokio::time::sleep(self.poll_interval).await;
+                    continue;
+                }
+            }
+
+            let height = self.next_height;
+            self.next_height += 1;
+            // heights without a block are skipped by the chain
+            let Some(block) = self
+                .call::<views::BlockView>("block", json!({ "block_id": height }))
+                .await?
+            else {
+                continue;
+            };
+            return Ok(Some(self.streamer_message(block).await?));
+        }
+    }
+}
+
+/// Replays `<block_height>.json` files holding serialized `StreamerMessage`s, e.g. blocks
+/// recorded from the lake for deterministic tests
+pub struct DirectoryBlockSource {
+    files: std::vec::IntoIter<PathBuf>,
+}
+
+impl DirectoryBlockSource {
+    /// Replay the blocks in `dir` at or above `start_block_height`, in height order
+    pub fn new(dir: &Path, start_block_height: BlockHeight) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("failed to read block directory {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(height) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<BlockHeight>().ok())
+            else {
+                continue;
+            };
+            if height >= start_block_height {
+                files.push((height, path));
+            }
+        }
+        files.sort();
+
+        let files: Vec<PathBuf> = files.into_iter().map(|(_, path)| path).collect();
+        Ok(Self {
+            files: files.into_iter(),
+        })
+    }
+}
+
+impl BlockSource for DirectoryBlockSource {
+    async fn next_block(&mut self) -> anyhow::Result<Option<StreamerMessage>> {
+        let Some(path) = self.files.next() else {
+            return Ok(None);
+        };
+        let json = tokio::fs::read(&path).await?;
+        let message = serde_json::from_slice(&json)
+            .with_context(|| format!("failed to parse recorded block {}", path.display()))?;
+        Ok(Some(message))
+    }
+}
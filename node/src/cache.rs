@@ -0,0 +1,265 @@
+//! Content-addressed cache of the datasets and models fetched from the blob store.
+//!
+//! Files live in the download directory named by their CID. Since every file is verified
+//! before it is renamed into place, a file named by a CID is complete and can be reused by any
+//! later job. The modification time records the last use, and least recently used files are
+//! evicted once the cache grows beyond its size limit. Files held by running jobs are never
+//! evicted by the node; `node cache prune` can only tell for its own process, so it is meant
+//! for a stopped node.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::cid::{Cid, CidExt};
+use crate::ipfs_service::IpfsService;
+
+#[derive(Debug, Clone, clap::Parser)]
+#[group(id = "cache-options")]
+pub struct Options {
+    /// Where fetched datasets and models are cached.
+    #[clap(long, env("IPFS_DOWNLOAD_DIR"), default_value = "./datasets/")]
+    pub download_dir: PathBuf,
+
+    /// The size in bytes the download directory is pruned to, least recently used files first.
+    #[clap(long, env("CACHE_MAX_BYTES"), default_value = "10737418240")]
+    pub cache_max_bytes: u64,
+}
+
+/// A cached file, as listed by [`entries`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub cid: String,
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+/// Number of leases per CID of the files running jobs use
+type InUse = Arc<Mutex<HashMap<String, usize>>>;
+
+#[derive(Clone)]
+pub struct DatasetCache {
+    ipfs: IpfsService,
+    dir: PathBuf,
+    max_bytes: u64,
+    in_use: InUse,
+}
+
+/// A cached file a job is using, it is not evicted until the lease is dropped
+pub struct CachedFile {
+    cid: String,
+    path: PathBuf,
+    in_use: InUse,
+}
+
+impl CachedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CachedFile {
+    fn drop(&mut self) {
+        let mut in_use = self.in_use.lock().unwrap();
+        if let Some(count) = in_use.get_mut(&self.cid) {
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(&self.cid);
+            }
+        }
+    }
+}
+
+impl DatasetCache {
+    /// `ipfs` has to save fetched files in the download directory of the `options`
+    pub fn new(ipfs: IpfsService, options: &Options) -> Self {
+        Self {
+            ipfs,
+            dir: options.download_dir.clone(),
+            max_bytes: options.cache_max_bytes,
+            in_use: InUse::default(),
+        }
+    }
+
+    /// The file with content `cid`, fetched from the blob store unless it is cached already
+    pub async fn get(&self, cid: &str) -> anyhow::Result<CachedFile> {
+        // the CID names the file, anything else could point outside the cache
+        Cid::parse(cid)?;
+        // lease the file before fetching it, so a concurrent eviction leaves it alone
+        let file = self.lease(cid);
+        if tokio::fs::try_exists(&file.path).await? {
+            tracing::debug!(cid, "using cached file");
+            let path = file.path.clone();
+            tokio::task::spawn_blocking(move || touch(&path)).await??;
+        } else {
+            self.ipfs.fetch_file(cid, cid).await?;
+        }
+
+        let cache = self.clone();
+        let evicted = tokio::task::spawn_blocking(move || cache.prune(cache.max_bytes)).await??;
+        for entry in evicted {
+            tracing::info!(cid = entry.cid, size = entry.size, "evicted cached file");
+        }
+        Ok(file)
+    }
+
+    /// Evict least recently used files that are not in use until the cache fits in `max_bytes`
+    pub fn prune(&self, max_bytes: u64) -> anyhow::Result<Vec<CacheEntry>> {
+        let in_use = self.in_use.lock().unwrap().clone();
+        prune(&self.dir, max_bytes, |cid| in_use.contains_key(cid))
+    }
+
+    fn lease(&self, cid: &str) -> CachedFile {
+        *self
+            .in_use
+            .lock()
+            .unwrap()
+            .entry(cid.to_string())
+            .or_default() += 1;
+        CachedFile {
+            cid: cid.to_string(),
+            path: self.dir.join(cid),
+            in_use: self.in_use.clone(),
+        }
+    }
+}
+
+/// Every cached file in `dir`, least recently used first
+pub fn entries(dir: &Path) -> anyhow::Result<Vec<CacheEntry>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).context("failed to read the cache directory"),
+    };
+    let mut entries = Vec::new();
+    for dir_entry in read_dir {
+        let dir_entry = dir_entry?;
+        // temporary downloads and job outputs share the directory but are not named by a CID
+        let Some(cid) = dir_entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if Cid::parse(&cid).is_err() {
+            continue;
+        }
+        let metadata = dir_entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        entries.push(CacheEntry {
+            cid,
+            size: metadata.len(),
+            last_used: metadata.modified()?,
+        });
+    }
+    entries.sort_by_key(|entry| entry.last_used);
+    Ok(entries)
+}
+
+/// Evict least recently used files from `dir` until it fits in `max_bytes`, skipping files
+/// `in_use` says are used. Returns the evicted files.
+pub fn prune(
+    dir: &Path,
+    max_bytes: u64,
+    in_use: impl Fn(&str) -> bool,
+) -> anyhow::Result<Vec<CacheEntry>> {
+    let entries = entries(dir)?;
+    let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut evicted = Vec::new();
+    for entry in entries {
+        if total <= max_bytes {
+            break;
+        }
+        if in_use(&entry.cid) {
+            continue;
+        }
+        match fs::remove_file(dir.join(&entry.cid)) {
+            Ok(()) => {}
+            // removed by someone else in the meantime
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).context("failed to evict cached file"),
+        }
+        total -= entry.size;
+        evicted.push(entry);
+    }
+    if total > max_bytes {
+        tracing::warn!(total, max_bytes, "files in use exceed the cache size");
+    }
+    Ok(evicted)
+}
+
+/// Record a use of a cached file
+fn touch(path: &Path) -> anyhow::Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+        .context("failed to update cached file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const OLDEST: &str = "Qmf412jQZiuVUtdgnB36FXFX7xg5V6KEbSJ4dpQuhkLyfD";
+    const MIDDLE: &str = "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e";
+    const NEWEST: &str = "QmX14HzEtaFNVGA4LfiaTF5cqgoKbN1x58DMCeW8QjcjKg";
+
+    /// A cache directory with three 10 byte files used one minute apart, and a stray file
+    fn cache_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let start = SystemTime::now() - Duration::from_secs(3600);
+        for (i, cid) in [OLDEST, MIDDLE, NEWEST].into_iter().enumerate() {
+            let path = dir.path().join(cid);
+            fs::write(&path, [0; 10]).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(start + Duration::from_secs(60 * i as u64))
+                .unwrap();
+        }
+        fs::write(dir.path().join(format!("{NEWEST}.predictions")), [0; 100]).unwrap();
+        dir
+    }
+
+    fn cids(entries: &[CacheEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.cid.as_str()).collect()
+    }
+
+    #[test]
+    fn lists_cached_files_least_recently_used_first() {
+        let dir = cache_dir();
+        let entries = entries(dir.path()).unwrap();
+        assert_eq!(cids(&entries), vec![OLDEST, MIDDLE, NEWEST]);
+        assert!(entries.iter().all(|entry| entry.size == 10));
+    }
+
+    #[test]
+    fn prunes_least_recently_used_files_first() {
+        let dir = cache_dir();
+        let evicted = prune(dir.path(), 15, |_| false).unwrap();
+        assert_eq!(cids(&evicted), vec![OLDEST, MIDDLE]);
+        assert_eq!(cids(&entries(dir.path()).unwrap()), vec![NEWEST]);
+    }
+
+    #[test]
+    fn touched_files_are_evicted_last() {
+        let dir = cache_dir();
+        touch(&dir.path().join(OLDEST)).unwrap();
+        let evicted = prune(dir.path(), 20, |_| false).unwrap();
+        assert_eq!(cids(&evicted), vec![MIDDLE]);
+    }
+
+    #[test]
+    fn files_in_use_are_not_evicted() {
+        let dir = cache_dir();
+        let evicted = prune(dir.path(), 0, |cid| cid == OLDEST).unwrap();
+        assert_eq!(cids(&evicted), vec![MIDDLE, NEWEST]);
+        assert_eq!(cids(&entries(dir.path()).unwrap()), vec![OLDEST]);
+    }
+}
@@ -0,0 +1,321 @@
+//! Content identifiers, just enough to check that the blocks IPFS returns are the ones asked for.
+//!
+//! CIDs are parsed and printed by the `cid` crate, the dag-pb and UnixFS messages of a file's
+//! blocks are decoded by prost. Supports sha2-256 and identity multihashes, and the raw and
+//! dag-pb codecs UnixFS files are made of.
+use std::fmt;
+
+use ::cid::multihash::Multihash;
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+pub use ::cid::Cid;
+
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+const IDENTITY: u64 = 0x00;
+const SHA2_256: u64 = 0x12;
+
+/// UnixFS node types holding file content, every other type (directories, symlinks, ...) is rejected
+const UNIXFS_RAW: i32 = 0;
+const UNIXFS_FILE: i32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CidError {
+    Invalid(String),
+    UnsupportedCodec(u64),
+    UnsupportedHash(u64),
+    HashMismatch { cid: String },
+    MalformedBlock { cid: String, reason: &'static str },
+    NotAFile { cid: String, unixfs_type: i32 },
+}
+
+impl fmt::Display for CidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CidError::Invalid(cid) => write!(f, "invalid CID {cid}"),
+            CidError::UnsupportedCodec(codec) => write!(f, "unsupported CID codec {codec:#x}"),
+            CidError::UnsupportedHash(code) => write!(f, "unsupported multihash {code:#x}"),
+            CidError::HashMismatch { cid } => write!(f, "content does not hash to {cid}"),
+            CidError::MalformedBlock { cid, reason } => {
+                write!(f, "malformed block {cid}: {reason}")
+            }
+            CidError::NotAFile { cid, unixfs_type } => {
+                write!(f, "{cid} is not a file, UnixFS type {unixfs_type}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CidError {}
+
+/// A block of a UnixFS file: the content it holds and, in order, the blocks with the rest of it
+#[derive(Debug)]
+pub struct FileBlock {
+    pub data: Vec<u8>,
+    pub links: Vec<Cid>,
+}
+
+/// `PBNode` of the dag-pb spec
+#[derive(Clone, PartialEq, Message)]
+struct PbNode {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    data: Option<Vec<u8>>,
+    #[prost(message, repeated, tag = "2")]
+    links: Vec<PbLink>,
+}
+
+/// `PBLink` of the dag-pb spec, the name and size of a link don't matter for reading a file
+#[derive(Clone, PartialEq, Message)]
+struct PbLink {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    hash: Option<Vec<u8>>,
+}
+
+/// `Data` of the UnixFS spec, carried in the data of a dag-pb node
+#[derive(Clone, PartialEq, Message)]
+struct UnixFsData {
+    #[prost(int32, optional, tag = "1")]
+    data_type: Option<i32>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    data: Option<Vec<u8>>,
+}
+
+/// What the node needs of a CID on top of the `cid` crate
+pub trait CidExt: Sized {
+    /// Parse a CID in its text form, e.g. `Qm...` or `bafy...`
+    fn parse(cid: &str) -> Result<Self, CidError>;
+
+    /// The CIDv1 of content stored as a single raw block, from its sha2-256 `digest`
+    fn from_sha256(digest: &[u8]) -> Self;
+
+    /// Check the sha2-256 `digest` of a whole content against a CID of a single raw block,
+    /// for content that was hashed while it was streamed
+    fn verify_sha256(&self, digest: &[u8]) -> Result<(), CidError>;
+
+    /// The block of an identity CID is the digest itself and never has to be fetched
+    fn inline_block(&self) -> Option<&[u8]>;
+
+    /// Check that `block` hashes to this CID
+    fn verify(&self, block: &[u8]) -> Result<(), CidError>;
+
+    /// Decode a verified block of a UnixFS file
+    fn decode_file_block(&self, block: &[u8]) -> Result<FileBlock, CidError>;
+}
+
+impl CidExt for Cid {
+    fn parse(cid: &str) -> Result<Self, CidError> {
+        Cid::try_from(cid).map_err(|_| CidError::Invalid(cid.to_string()))
+    }
+
+    fn from_sha256(digest: &[u8]) -> Self {
+        let multihash = Multihash::wrap(SHA2_256, digest).expect("a sha2-256 digest fits");
+        Cid::new_v1(RAW, multihash)
+    }
+
+    fn verify_sha256(&self, digest: &[u8]) -> Result<(), CidError> {
+        if self.codec() != RAW {
+            return Err(CidError::UnsupportedCodec(self.codec()));
+        }
+        if self.hash().code() != SHA2_256 {
+            return Err(CidError::UnsupportedHash(self.hash().code()));
+        }
+        if digest != self.hash().digest() {
+            return Err(CidError::HashMismatch {
+                cid: self.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn inline_block(&self) -> Option<&[u8]> {
+        (self.hash().code() == IDENTITY).then_some(self.hash().digest())
+    }
+
+    fn verify(&self, block: &[u8]) -> Result<(), CidError> {
+        let digest = self.hash().digest();
+        let matches = match self.hash().code() {
+            IDENTITY => block == digest,
+            SHA2_256 => Sha256::digest(block).as_slice() == digest,
+            code => return Err(CidError::UnsupportedHash(code)),
+        };
+        if !matches {
+            return Err(CidError::HashMismatch {
+                cid: self.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn decode_file_block(&self, block: &[u8]) -> Result<FileBlock, CidError> {
+        match self.codec() {
+            RAW => Ok(FileBlock {
+                data: block.to_vec(),
+                links: Vec::new(),
+            }),
+            DAG_PB => decode_dag_pb(self, block),
+            codec => Err(CidError::UnsupportedCodec(codec)),
+        }
+    }
+}
+
+fn decode_dag_pb(cid: &Cid, block: &[u8]) -> Result<FileBlock, CidError> {
+    let malformed = |reason| CidError::MalformedBlock {
+        cid: cid.to_string(),
+        reason,
+    };
+    let node = PbNode::decode(block).map_err(|_| malformed("invalid dag-pb node"))?;
+    let links = node
+        .links
+        .into_iter()
+        .map(|link| {
+            let hash = link.hash.ok_or_else(|| malformed("link without a hash"))?;
+            Cid::try_from(hash.as_slice()).map_err(|_| malformed("invalid link CID"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let unixfs = node.data.ok_or_else(|| malformed("no UnixFS data"))?;
+    let unixfs =
+        UnixFsData::decode(unixfs.as_slice()).map_err(|_| malformed("invalid UnixFS data"))?;
+    let unixfs_type = unixfs.data_type.unwrap_or(UNIXFS_RAW);
+    if unixfs_type != UNIXFS_RAW && unixfs_type != UNIXFS_FILE {
+        return Err(CidError::NotAFile {
+            cid: cid.to_string(),
+            unixfs_type,
+        });
+    }
+    Ok(FileBlock {
+        data: unixfs.data.unwrap_or_default(),
+        links,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::cid::multibase::Base;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // `ipfs add` of "hello world" in a single block
+    const HELLO_V0: &str = "Qmf412jQZiuVUtdgnB36FXFX7xg5V6KEbSJ4dpQuhkLyfD";
+    const HELLO_BLOCK: &str = "0a110802120b68656c6c6f20776f726c64180b";
+    // "hello world" as a raw block
+    const HELLO_RAW: &str = "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e";
+    // a file node linking to the raw leaves "hello " and "world"
+    const CHUNKED_V0: &str = "QmX14HzEtaFNVGA4LfiaTF5cqgoKbN1x58DMCeW8QjcjKg";
+    const CHUNKED_BLOCK: &str = "122a0a24015512205e3235a8346e5a4585f8c58562f5052b8fe26a3bb122e1e96c76784964dfc46112001806122a0a2401551220486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7120018050a080802180b20062005";
+
+    #[test]
+    fn verifies_a_cid_v0_file() {
+        let cid = Cid::parse(HELLO_V0).unwrap();
+        let block = hex(HELLO_BLOCK);
+        cid.verify(&block).unwrap();
+        let file = cid.decode_file_block(&block).unwrap();
+        assert_eq!(file.data, b"hello world");
+        assert!(file.links.is_empty());
+        assert_eq!(cid.to_string(), HELLO_V0);
+    }
+
+    #[test]
+    fn verifies_a_cid_v1_raw_block() {
+        let cid = Cid::parse(HELLO_RAW).unwrap();
+        cid.verify(b"hello world").unwrap();
+        let file = cid.decode_file_block(b"hello world").unwrap();
+        assert_eq!(file.data, b"hello world");
+
+        assert_eq!(cid.to_string(), HELLO_RAW);
+        assert_eq!(Cid::parse(&HELLO_RAW.to_ascii_uppercase()).unwrap(), cid);
+        let base58 = cid.to_string_of_base(Base::Base58Btc).unwrap();
+        assert!(base58.starts_with('z'));
+        assert_eq!(Cid::parse(&base58).unwrap(), cid);
+    }
+
+    #[test]
+    fn verifies_streamed_content_by_digest() {
+        let digest = Sha256::digest(b"hello world");
+        let cid = Cid::from_sha256(&digest);
+        assert_eq!(cid.to_string(), HELLO_RAW);
+        cid.verify_sha256(&digest).unwrap();
+        assert!(matches!(
+            cid.verify_sha256(&Sha256::digest(b"hello world!")),
+            Err(CidError::HashMismatch { .. })
+        ));
+        assert!(matches!(
+            Cid::parse(HELLO_V0).unwrap().verify_sha256(&digest),
+            Err(CidError::UnsupportedCodec(DAG_PB))
+        ));
+    }
+
+    #[test]
+    fn follows_links_to_raw_leaves() {
+        let cid = Cid::parse(CHUNKED_V0).unwrap();
+        let block = hex(CHUNKED_BLOCK);
+        cid.verify(&block).unwrap();
+        let file = cid.decode_file_block(&block).unwrap();
+        assert!(file.data.is_empty());
+        assert_eq!(file.links.len(), 2);
+        file.links[0].verify(b"hello ").unwrap();
+        file.links[1].verify(b"world").unwrap();
+    }
+
+    #[test]
+    fn rejects_content_that_does_not_match() {
+        let cid = Cid::parse(HELLO_RAW).unwrap();
+        assert!(matches!(
+            cid.verify(b"hello world!"),
+            Err(CidError::HashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_cids() {
+        for cid in [
+            "",
+            "Qm",
+            "not a cid",
+            "bafk!",
+            "zzzz",
+            "QmInvalidInvalidInvalidInvalidInvalidInvali",
+        ] {
+            assert!(Cid::parse(cid).is_err(), "{cid}");
+        }
+    }
+
+    #[test]
+    fn rejects_damaged_blocks_without_panicking() {
+        let cid = Cid::parse(CHUNKED_V0).unwrap();
+        let block = hex(CHUNKED_BLOCK);
+        for len in 0..block.len() {
+            let _ = cid.decode_file_block(&block[..len]);
+        }
+        for i in 0..block.len() {
+            for flip in [0x01, 0x80, 0xff] {
+                let mut damaged = block.clone();
+                damaged[i] ^= flip;
+                let _ = cid.decode_file_block(&damaged);
+            }
+        }
+        assert!(matches!(
+            cid.decode_file_block(&block[..10]),
+            Err(CidError::MalformedBlock { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_directories() {
+        // a dag-pb node with UnixFS data of type directory
+        let block = [0x0a, 0x02, 0x08, 0x01];
+        let cid = Cid::new_v0(Multihash::wrap(SHA2_256, &Sha256::digest(block)).unwrap()).unwrap();
+        cid.verify(&block).unwrap();
+        assert!(matches!(
+            cid.decode_file_block(&block),
+            Err(CidError::NotAFile { unixfs_type: 1, .. })
+        ));
+    }
+}
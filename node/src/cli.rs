@@ -0,0 +1,236 @@
+use crate::cache::{self, DatasetCache};
+use crate::config::Config;
+use crate::ipfs;
+use crate::ipfs_service;
+use crate::mirror::ContractMirror;
+use crate::pinning::{self, Replicator};
+use crate::queue::RequestQueue;
+use crate::storage::{self, Storage};
+use crate::worker::{self, Worker};
+use crate::{backfill, indexer, metrics, model, web};
+
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+use near_account_id::AccountId;
+use near_crypto::SecretKey;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+#[derive(Parser, Debug)]
+#[command(name = "veilnet-node", about = "VeilNetFL worker node")]
+pub enum Cli {
+    /// Index the contract and process the training and inference requests assigned to this worker
+    Start(Box<StartOptions>),
+    /// Inspect and clean up the cache of downloaded datasets and models.
+    /// Pruning does not know which files a running node uses, stop the node first.
+    Cache {
+        #[clap(flatten)]
+        cache_options: cache::Options,
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+/// Options of `start`, boxed in [`Cli`] so the other commands stay small
+#[derive(Args, Debug)]
+pub struct StartOptions {
+    /// The account id of this worker
+    #[clap(long, env("VEILNET_ACCOUNT_ID"))]
+    pub account_id: AccountId,
+    /// The secret key used to sign transactions for the worker account
+    #[clap(long, env("VEILNET_ACCOUNT_SK"))]
+    pub account_sk: SecretKey,
+    /// The VeilNetFL contract account id
+    #[clap(long, env("VEILNET_CONTRACT_ID"))]
+    pub contract_id: AccountId,
+    /// NEAR RPC address
+    #[clap(
+        long,
+        env("VEILNET_NEAR_RPC"),
+        default_value = "https://rpc.testnet.near.org"
+    )]
+    pub near_rpc: String,
+    /// Directory for the worker's local state, e.g. the job queue and indexer progress
+    #[clap(long, env("VEILNET_DATA_DIR"), default_value = "./data")]
+    pub data_dir: PathBuf,
+    #[clap(flatten)]
+    pub indexer_options: indexer::Options,
+    #[clap(flatten)]
+    pub storage_options: storage::Options,
+    #[clap(flatten)]
+    pub ipfs_options: ipfs::Options,
+    #[clap(flatten)]
+    pub ipfs_service_options: ipfs_service::Options,
+    #[clap(flatten)]
+    pub cache_options: cache::Options,
+    #[clap(flatten)]
+    pub pinning_options: pinning::Options,
+    #[clap(flatten)]
+    pub model_options: model::Options,
+    #[clap(flatten)]
+    pub web_options: web::Options,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// List the cached files, least recently used first
+    List,
+    /// Evict least recently used files until the cache fits in its maximum size
+    Prune {
+        /// Evict every cached file
+        #[clap(long)]
+        all: bool,
+    },
+}
+
+pub fn run(cmd: Cli) -> anyhow::Result<()> {
+    match cmd {
+        Cli::Start(options) => {
+            let StartOptions {
+                account_id,
+                account_sk,
+                contract_id,
+                near_rpc,
+                data_dir,
+                indexer_options,
+                storage_options,
+                ipfs_options,
+                ipfs_service_options,
+                cache_options,
+                pinning_options,
+                model_options,
+                web_options,
+            } = *options;
+            metrics::register();
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?;
+            let db = sled::open(data_dir.join("db"))?;
+            let queue = RequestQueue::open(&db)?;
+            let mirror = ContractMirror::default();
+
+            let config = Config::new(account_id, account_sk, contract_id, near_rpc);
+
+            // catch up on requests that were added while this worker was not indexing
+            let rpc_client = near_fetch::Client::new(&config.rpc_url);
+            match rt.block_on(backfill::run(&rpc_client, &config, &queue, &mirror)) {
+                Ok(added) => tracing::info!(added, "backfilled requests from the contract"),
+                Err(err) => tracing::warn!(?err, "failed to backfill requests from the contract"),
+            }
+
+            let store = Storage::new(&storage_options, &ipfs_options)?;
+            // the service spawns its task, it needs the runtime
+            let (ipfs_handle, ipfs) = {
+                let _runtime = rt.enter();
+                ipfs_service::run(
+                    store.clone(),
+                    &cache_options.download_dir,
+                    &ipfs_service_options,
+                )
+            };
+            let cache = DatasetCache::new(ipfs.clone(), &cache_options);
+            let replicator = Replicator::new(ipfs.clone(), &store, &pinning_options)?;
+            let contract_id: near_lake_primitives::AccountId =
+                config.contract_id.as_str().parse()?;
+            let worker_account_id: near_lake_primitives::AccountId =
+                config.account_id.as_str().parse()?;
+            let rpc_url = config.rpc_url.clone();
+            let worker = Worker::new(worker::Dependencies {
+                config,
+                ipfs,
+                replicator,
+                cache,
+                queue: queue.clone(),
+                mirror: mirror.clone(),
+                model_options,
+            });
+
+            rt.block_on(async {
+                let (indexer_handle, indexer) = indexer::run(
+                    &indexer_options,
+                    &contract_id,
+                    &worker_account_id,
+                    &queue,
+                    &mirror,
+                    &db,
+                )?;
+                let web_state = web::AppState {
+                    indexer,
+                    queue: queue.clone(),
+                    store,
+                    rpc_url,
+                    http: reqwest::Client::new(),
+                };
+
+                let (shutdown_tx, shutdown_rx) = watch::channel(false);
+                let mut sigterm = signal(SignalKind::terminate())?;
+                tokio::spawn({
+                    let shutdown_tx = shutdown_tx.clone();
+                    async move {
+                        tokio::select! {
+                            _ = sigterm.recv() => tracing::info!("received SIGTERM, shutting down"),
+                            _ = tokio::signal::ctrl_c() => tracing::info!("received SIGINT, shutting down"),
+                        }
+                        let _ = shutdown_tx.send(true);
+                    }
+                });
+                let web = tokio::spawn({
+                    let shutdown = shutdown_rx.clone();
+                    async move { web::serve(&web_options, web_state, shutdown).await }
+                });
+
+                // stop everything else even when the worker failed, then report the first error
+                let worker_result = worker.run(shutdown_rx).await;
+                let _ = shutdown_tx.send(true);
+                let indexer_result = indexer_handle.shutdown().await;
+                let ipfs_result = ipfs_handle.shutdown().await;
+                let web_result = web.await.map_err(anyhow::Error::from).and_then(|r| r);
+                worker_result
+                    .and(indexer_result)
+                    .and(ipfs_result)
+                    .and(web_result)
+            })
+        }
+        Cli::Cache {
+            cache_options,
+            command,
+        } => {
+            let dir = &cache_options.download_dir;
+            match command {
+                CacheCommand::List => {
+                    let entries = cache::entries(dir)?;
+                    for entry in &entries {
+                        let idle = entry.last_used.elapsed().unwrap_or_default();
+                        println!(
+                            "{}\t{} bytes\tused {}s ago",
+                            entry.cid,
+                            entry.size,
+                            idle.as_secs()
+                        );
+                    }
+                    let total: u64 = entries.iter().map(|entry| entry.size).sum();
+                    println!(
+                        "{} files, {total} of {} bytes",
+                        entries.len(),
+                        cache_options.cache_max_bytes
+                    );
+                }
+                CacheCommand::Prune { all } => {
+                    let max_bytes = if all {
+                        0
+                    } else {
+                        cache_options.cache_max_bytes
+                    };
+                    let evicted = cache::prune(dir, max_bytes, |_| false)?;
+                    let freed: u64 = evicted.iter().map(|entry| entry.size).sum();
+                    for entry in &evicted {
+                        println!("{}\t{} bytes", entry.cid, entry.size);
+                    }
+                    println!("evicted {} files, {freed} bytes", evicted.len());
+                }
+            }
+            Ok(())
+        }
+    }
+}
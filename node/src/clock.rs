@@ -0,0 +1,27 @@
+//! Time sources, injected so staleness checks can be driven by a fake clock in tests.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for measuring how long ago something happened on this node
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time since the unix epoch, for comparing with block timestamps
+    fn unix_time(&self) -> Duration;
+}
+
+/// The clock of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_time(&self) -> Duration {
+        // a system clock set before 1970 reads as the epoch, which makes every block look recent
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
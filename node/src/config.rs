@@ -0,0 +1,28 @@
+use near_account_id::AccountId;
+use near_crypto::{InMemorySigner, SecretKey};
+
+/// Identity of the worker and the contract it serves
+#[derive(Clone)]
+pub struct Config {
+    pub account_id: AccountId,
+    pub signer: InMemorySigner,
+    pub contract_id: AccountId,
+    pub rpc_url: String,
+}
+
+impl Config {
+    pub fn new(
+        account_id: AccountId,
+        secret_key: SecretKey,
+        contract_id: AccountId,
+        rpc_url: String,
+    ) -> Self {
+        let signer = InMemorySigner::from_secret_key(account_id.clone(), secret_key);
+        Self {
+            account_id,
+            signer,
+            contract_id,
+            rpc_url,
+        }
+    }
+}
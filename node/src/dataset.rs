@@ -0,0 +1,525 @@
+//! Binary container for encrypted datasets shared between clients and workers.
+//!
+//! All integers are little endian. A dataset file is laid out as:
+//!
+//! | field         | size                | description                                  |
+//! |---------------|---------------------|----------------------------------------------|
+//! | magic         | 4 bytes             | `VNFL`                                       |
+//! | version       | u16                 | format version, currently [`FORMAT_VERSION`] |
+//! | header length | u32                 | length of the JSON header that follows       |
+//! | header        | header length bytes | JSON encoded [`DatasetHeader`]               |
+//! | cells         | ...                 | `row_count * columns.len()` cells, row major |
+//!
+//! Every cell is a u32 length followed by a ciphertext serialized with
+//! `tfhe::safe_serialization::safe_serialize` under the header's parameter set.
+//! The web client writes this format in `ui/lib/encrypt.ts`.
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use tfhe::shortint::parameters::PARAM_MESSAGE_2_CARRY_2_KS_PBS;
+use tfhe::{Config, ConfigBuilder};
+
+pub const MAGIC: &[u8; 4] = b"VNFL";
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Upper bound for the JSON header, anything bigger is not a dataset we produced
+const MAX_HEADER_LEN: u32 = 1 << 20;
+/// Upper bound for a single serialized ciphertext
+const MAX_CELL_LEN: u32 = 1 << 30;
+
+/// The FHE parameter set the dataset was encrypted with
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum FheParameters {
+    Message2Carry2KsPbs,
+}
+
+impl FheParameters {
+    pub fn config(&self) -> Config {
+        match self {
+            FheParameters::Message2Carry2KsPbs => {
+                ConfigBuilder::with_custom_parameters(PARAM_MESSAGE_2_CARRY_2_KS_PBS).build()
+            }
+        }
+    }
+}
+
+/// The type of the ciphertexts stored in a column
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    FheUint8,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub column_type: ColumnType,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DatasetHeader {
+    pub parameters: FheParameters,
+    pub columns: Vec<Column>,
+    pub row_count: u64,
+    pub label_column: Option<u32>, // None for inference inputs
+}
+
+impl DatasetHeader {
+    pub fn validate(&self) -> Result<(), DatasetError> {
+        if self.columns.is_empty() {
+            return Err(DatasetError::InvalidHeader(
+                "dataset has no columns".to_string(),
+            ));
+        }
+        if let Some(label) = self.label_column {
+            if label as usize >= self.columns.len() {
+                return Err(DatasetError::LabelOutOfRange {
+                    label,
+                    columns: self.columns.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of feature columns, i.e. every column except the label
+    pub fn feature_count(&self) -> usize {
+        self.columns.len() - usize::from(self.label_column.is_some())
+    }
+
+    /// Split a row into its features and, if the dataset is labelled, its label
+    pub fn split_label(&self, mut row: Vec<Vec<u8>>) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        match self.label_column {
+            Some(label) => {
+                let label = row.remove(label as usize);
+                (row, Some(label))
+            }
+            None => (row, None),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DatasetError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    InvalidHeader(String),
+    LabelOutOfRange { label: u32, columns: usize },
+    ColumnCountMismatch { expected: usize, actual: usize },
+    RowCountMismatch { expected: u64, actual: u64 },
+    CellTooLarge(u64),
+    TrailingData,
+}
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatasetError::Io(err) => write!(f, "io error: {err}"),
+            DatasetError::BadMagic => write!(f, "not a dataset file, bad magic"),
+            DatasetError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported dataset version {version}, expected {FORMAT_VERSION}"
+                )
+            }
+            DatasetError::InvalidHeader(err) => write!(f, "invalid dataset header: {err}"),
+            DatasetError::LabelOutOfRange { label, columns } => {
+                write!(
+                    f,
+                    "label column {label} is out of range for {columns} columns"
+                )
+            }
+            DatasetError::ColumnCountMismatch { expected, actual } => {
+                write!(f, "expected {expected} columns per row, got {actual}")
+            }
+            DatasetError::RowCountMismatch { expected, actual } => {
+                write!(f, "expected {expected} rows, got {actual}")
+            }
+            DatasetError::CellTooLarge(len) => write!(f, "cell of {len} bytes is too large"),
+            DatasetError::TrailingData => write!(f, "unexpected data after the last row"),
+        }
+    }
+}
+
+impl std::error::Error for DatasetError {}
+
+impl From<io::Error> for DatasetError {
+    fn from(err: io::Error) -> Self {
+        DatasetError::Io(err)
+    }
+}
+
+/// Reads and validates a dataset, yielding one row of serialized ciphertexts at a time
+pub struct DatasetReader<R> {
+    reader: R,
+    header: DatasetHeader,
+    rows_read: u64,
+}
+
+impl<R: Read> DatasetReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, DatasetError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(DatasetError::BadMagic);
+        }
+
+        let version = read_u16(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(DatasetError::UnsupportedVersion(version));
+        }
+
+        let header_len = read_u32(&mut reader)?;
+        if header_len > MAX_HEADER_LEN {
+            return Err(DatasetError::InvalidHeader(format!(
+                "header of {header_len} bytes is too large"
+            )));
+        }
+        let mut header = vec![0u8; header_len as usize];
+        reader.read_exact(&mut header)?;
+        let header: DatasetHeader = serde_json::from_slice(&header)
+            .map_err(|e| DatasetError::InvalidHeader(e.to_string()))?;
+        header.validate()?;
+
+        Ok(Self {
+            reader,
+            header,
+            rows_read: 0,
+        })
+    }
+
+    pub fn header(&self) -> &DatasetHeader {
+        &self.header
+    }
+
+    /// Read the next row, `None` once all `row_count` rows have been read
+    pub fn read_row(&mut self) -> Result<Option<Vec<Vec<u8>>>, DatasetError> {
+        if self.rows_read == self.header.row_count {
+            // the header is the source of truth, anything after the last row is corrupt
+            let mut byte = [0u8; 1];
+            if self.reader.read(&mut byte)? != 0 {
+                return Err(DatasetError::TrailingData);
+            }
+            return Ok(None);
+        }
+
+        let mut row = Vec::with_capacity(self.header.columns.len());
+        for _ in 0..self.header.columns.len() {
+            let len = match read_u32(&mut self.reader) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(DatasetError::RowCountMismatch {
+                        expected: self.header.row_count,
+                        actual: self.rows_read,
+                    });
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if len > MAX_CELL_LEN {
+                return Err(DatasetError::CellTooLarge(len as u64));
+            }
+            // the length is untrusted, only allocate for the bytes that are actually there
+            let mut cell = Vec::new();
+            (&mut self.reader).take(len as u64).read_to_end(&mut cell)?;
+            if cell.len() < len as usize {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            row.push(cell);
+        }
+        self.rows_read += 1;
+        Ok(Some(row))
+    }
+}
+
+impl<R: Read> Iterator for DatasetReader<R> {
+    type Item = Result<Vec<Vec<u8>>, DatasetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_row().transpose()
+    }
+}
+
+/// Writes a dataset, checking every row against the header
+pub struct DatasetWriter<W: Write> {
+    writer: W,
+    header: DatasetHeader,
+    rows_written: u64,
+}
+
+impl<W: Write> DatasetWriter<W> {
+    pub fn new(mut writer: W, header: DatasetHeader) -> Result<Self, DatasetError> {
+        header.validate()?;
+        let encoded =
+            serde_json::to_vec(&header).map_err(|e| DatasetError::InvalidHeader(e.to_string()))?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&(encoded.len() as u32).to_le_bytes())?;
+        writer.write_all(&encoded)?;
+
+        Ok(Self {
+            writer,
+            header,
+            rows_written: 0,
+        })
+    }
+
+    pub fn write_row(&mut self, row: &[Vec<u8>]) -> Result<(), DatasetError> {
+        if row.len() != self.header.columns.len() {
+            return Err(DatasetError::ColumnCountMismatch {
+                expected: self.header.columns.len(),
+                actual: row.len(),
+            });
+        }
+        if self.rows_written == self.header.row_count {
+            return Err(DatasetError::RowCountMismatch {
+                expected: self.header.row_count,
+                actual: self.rows_written + 1,
+            });
+        }
+        for cell in row {
+            if cell.len() > MAX_CELL_LEN as usize {
+                return Err(DatasetError::CellTooLarge(cell.len() as u64));
+            }
+            self.writer.write_all(&(cell.len() as u32).to_le_bytes())?;
+            self.writer.write_all(cell)?;
+        }
+        self.rows_written += 1;
+        Ok(())
+    }
+
+    /// Check that every row promised by the header was written and flush the writer
+    pub fn finish(mut self) -> Result<W, DatasetError> {
+        if self.rows_written != self.header.row_count {
+            return Err(DatasetError::RowCountMismatch {
+                expected: self.header.row_count,
+                actual: self.rows_written,
+            });
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(columns: usize, row_count: u64, label_column: Option<u32>) -> DatasetHeader {
+        DatasetHeader {
+            parameters: FheParameters::Message2Carry2KsPbs,
+            columns: (0..columns)
+                .map(|i| Column {
+                    name: format!("x{i}"),
+                    column_type: ColumnType::FheUint8,
+                })
+                .collect(),
+            row_count,
+            label_column,
+        }
+    }
+
+    /// Rows of `columns` cells with distinct contents and lengths
+    fn rows(count: usize, columns: usize) -> Vec<Vec<Vec<u8>>> {
+        (0..count)
+            .map(|r| (0..columns).map(|c| vec![r as u8; c + 1]).collect())
+            .collect()
+    }
+
+    fn write(header: DatasetHeader, rows: &[Vec<Vec<u8>>]) -> Vec<u8> {
+        let mut writer = DatasetWriter::new(Vec::new(), header).unwrap();
+        for row in rows {
+            writer.write_row(row).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn read_all(bytes: &[u8]) -> Result<Vec<Vec<Vec<u8>>>, DatasetError> {
+        DatasetReader::new(bytes)?.collect()
+    }
+
+    /// The bytes of a dataset up to and including its header
+    fn header_bytes(header: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(header);
+        bytes
+    }
+
+    #[test]
+    fn round_trips_labelled_datasets() {
+        let header = header(3, 4, Some(1));
+        let rows = rows(4, 3);
+        let bytes = write(header.clone(), &rows);
+
+        let mut reader = DatasetReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.header(), &header);
+        for row in &rows {
+            assert_eq!(reader.read_row().unwrap().as_ref(), Some(row));
+        }
+        assert!(reader.read_row().unwrap().is_none());
+
+        let (features, label) = header.split_label(rows[2].clone());
+        assert_eq!(features, vec![vec![2], vec![2, 2, 2]]);
+        assert_eq!(label, Some(vec![2, 2]));
+        assert_eq!(header.feature_count(), 2);
+    }
+
+    #[test]
+    fn round_trips_unlabelled_and_empty_datasets() {
+        let rows = rows(2, 1);
+        let bytes = write(header(1, 2, None), &rows);
+        assert_eq!(read_all(&bytes).unwrap(), rows);
+        assert_eq!(header(1, 2, None).feature_count(), 1);
+
+        let bytes = write(header(2, 0, Some(0)), &[]);
+        assert!(read_all(&bytes).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = write(header(1, 1, None), &rows(1, 1));
+        bytes[..4].copy_from_slice(b"PK\x03\x04");
+        assert!(matches!(read_all(&bytes), Err(DatasetError::BadMagic)));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = write(header(1, 1, None), &rows(1, 1));
+        bytes[4..6].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(
+            read_all(&bytes),
+            Err(DatasetError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert!(matches!(
+            read_all(&header_bytes(b"{\"columns\": 3}")),
+            Err(DatasetError::InvalidHeader(_))
+        ));
+        let mut bytes = header_bytes(b"");
+        bytes[6..10].copy_from_slice(&(MAX_HEADER_LEN + 1).to_le_bytes());
+        assert!(matches!(
+            read_all(&bytes),
+            Err(DatasetError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            DatasetWriter::new(Vec::new(), header(0, 0, None)),
+            Err(DatasetError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_labels_out_of_range() {
+        assert!(matches!(
+            DatasetWriter::new(Vec::new(), header(2, 0, Some(2))),
+            Err(DatasetError::LabelOutOfRange {
+                label: 2,
+                columns: 2
+            })
+        ));
+        let encoded = serde_json::to_vec(&header(2, 0, Some(5))).unwrap();
+        assert!(matches!(
+            read_all(&header_bytes(&encoded)),
+            Err(DatasetError::LabelOutOfRange {
+                label: 5,
+                columns: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_rows_with_the_wrong_column_count() {
+        let mut writer = DatasetWriter::new(Vec::new(), header(2, 1, None)).unwrap();
+        assert!(matches!(
+            writer.write_row(&rows(1, 3)[0]),
+            Err(DatasetError::ColumnCountMismatch {
+                expected: 2,
+                actual: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_too_few_rows() {
+        let mut writer = DatasetWriter::new(Vec::new(), header(1, 3, None)).unwrap();
+        writer.write_row(&rows(1, 1)[0]).unwrap();
+        assert!(matches!(
+            writer.finish(),
+            Err(DatasetError::RowCountMismatch {
+                expected: 3,
+                actual: 1
+            })
+        ));
+
+        // a file cut off after its second row
+        let mut bytes = write(header(1, 3, None), &rows(3, 1));
+        bytes.truncate(bytes.len() - 5);
+        assert!(matches!(
+            read_all(&bytes),
+            Err(DatasetError::RowCountMismatch {
+                expected: 3,
+                actual: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_too_many_rows() {
+        let mut writer = DatasetWriter::new(Vec::new(), header(1, 1, None)).unwrap();
+        writer.write_row(&rows(1, 1)[0]).unwrap();
+        assert!(matches!(
+            writer.write_row(&rows(1, 1)[0]),
+            Err(DatasetError::RowCountMismatch {
+                expected: 1,
+                actual: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut bytes = write(header(1, 1, None), &rows(1, 1));
+        bytes.push(0);
+        assert!(matches!(read_all(&bytes), Err(DatasetError::TrailingData)));
+    }
+
+    #[test]
+    fn rejects_oversized_cells() {
+        let encoded = serde_json::to_vec(&header(1, 1, None)).unwrap();
+        let mut bytes = header_bytes(&encoded);
+        bytes.extend_from_slice(&(MAX_CELL_LEN + 1).to_le_bytes());
+        assert!(matches!(
+            read_all(&bytes),
+            Err(DatasetError::CellTooLarge(len)) if len == MAX_CELL_LEN as u64 + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_cells_shorter_than_their_length() {
+        let encoded = serde_json::to_vec(&header(1, 1, None)).unwrap();
+        let mut bytes = header_bytes(&encoded);
+        bytes.extend_from_slice(&MAX_CELL_LEN.to_le_bytes());
+        bytes.extend_from_slice(&[0; 16]);
+        assert!(matches!(
+            read_all(&bytes),
+            Err(DatasetError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
@@ -1,17 +1,31 @@
-use crate::types::{LatestBlockHeight, ModelData, RequestArguments, RequestQueue};
-use chrono::{DateTime, LocalResult, TimeZone, Utc};
+use crate::block_source::{BlockSource, BlockSourceKind, DirectoryBlockSource, RpcBlockSource};
+use crate::clock::{Clock, SystemClock};
+use crate::metrics;
+use crate::mirror::{ContractMirror, ProposalKind};
+use crate::queue::RequestQueue;
+use crate::types::{
+    BaseFeeArguments, ContractEvent, InferenceArguments, InferenceRequest, LatestBlockHeight,
+    ModelData, ProposalCreatedData, ProposalExecutedData, RemoveWorkerArguments, RequestAddedData,
+    RequestArguments, RequestIdArguments, StakeAmountArguments, TrainingRequest, VoteArguments,
+};
+use anyhow::Context as _;
+use futures::future::BoxFuture;
 use near_lake_context_derive::LakeContext;
 use near_lake_framework::{near_indexer_primitives::types::BlockHeight, LakeBuilder};
-use near_lake_primitives::actions::ActionMetaDataExt;
+use near_lake_primitives::actions::{Action, ActionMetaDataExt};
+use near_lake_primitives::block::Block;
 use near_lake_primitives::receipts::ExecutionStatus;
 use near_lake_primitives::AccountId;
-use std::thread::JoinHandle;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::{
     sync::Arc,
     time::{Duration, Instant},
-    ops::Mul
 };
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
 /// The code is inspired from the chain signature mpc indexer https://github.com/near/mpc/blob/develop/chain-signatures/node/src/indexer.rs
 
 #[derive(Debug, Clone, clap::Parser)]
@@ -22,7 +36,8 @@ pub struct Options {
     #[clap(long, env("INDEXER_START_BLOCK_HEIGHT"), default_value = "178930751")]
     pub start_block_height: u64,
 
-    /// The amount of time before we should that our indexer is behind.
+    /// The threshold in seconds after which the indexer is behind: the last block it handled
+    /// was produced longer ago than this.
     #[clap(long, env("INDEXER_BEHIND_THRESHOLD"), default_value = "200")]
     pub behind_threshold: u64,
 
@@ -30,36 +45,124 @@ pub struct Options {
     #[clap(long, env("INDEXER_RUNNING_THRESHOLD"), default_value = "300")]
     pub running_threshold: u64,
 
-    #[clap(subcommand)]
+    /// The chain whose lake the indexer reads blocks from.
+    #[clap(long, env("INDEXER_CHAIN_ID"), value_enum, default_value = "testnet")]
     pub chain_id: ChainId,
+
+    /// Discard the saved progress and start indexing from this block height instead.
+    /// Applied once, the saved progress is kept on later starts with the same height.
+    #[clap(long, env("INDEXER_RESET_FROM"))]
+    pub reset_from: Option<u64>,
+
+    /// Where the indexer reads blocks from.
+    #[clap(long, env("INDEXER_BLOCK_SOURCE"), value_enum, default_value = "lake")]
+    pub block_source: BlockSourceKind,
+
+    /// The JSON-RPC node polled by the `rpc` block source, e.g. a local near-sandbox.
+    #[clap(long, env("INDEXER_RPC_URL"), default_value = "http://localhost:3030")]
+    pub rpc_url: String,
+
+    /// How often the `rpc` block source polls for new final blocks, in milliseconds.
+    #[clap(long, env("INDEXER_POLL_INTERVAL_MS"), default_value = "1000")]
+    pub poll_interval_ms: u64,
+
+    /// Directory of recorded `<block_height>.json` blocks replayed by the `directory` block source.
+    #[clap(long, env("INDEXER_BLOCKS_DIR"))]
+    pub blocks_dir: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub chain: Option<ChainCommand>,
+}
+
+impl Options {
+    /// The chain to index, the trailing subcommand wins over `--chain-id`
+    pub fn chain_id(&self) -> ChainId {
+        match self.chain {
+            Some(ChainCommand::Mainnet) => ChainId::Mainnet,
+            Some(ChainCommand::Testnet) => ChainId::Testnet,
+            None => self.chain_id.clone(),
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, PartialEq, Eq)]
+pub enum ChainId {
+    Mainnet,
+    Testnet,
 }
-#[derive(clap::Parser, Debug, Clone)]
-pub(crate) enum ChainId {
+
+/// The chain given as a subcommand, e.g. `start testnet`, as it was before `--chain-id`.
+/// Hidden, it only keeps existing invocations working.
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum ChainCommand {
+    #[command(hide = true)]
     Mainnet,
+    #[command(hide = true)]
     Testnet,
 }
 
+/// How often the supervisor checks that the indexer still makes progress
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many blocks an action is retried for when its receipt is missing from the block
+const MAX_RECEIPT_ATTEMPTS: u32 = 10;
+
+/// Why an action on the contract was not applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SkipReason {
+    MalformedArguments,
+    InvalidAccountId,
+    MissingEvent,
+    MissingReceipt,
+}
+
+impl SkipReason {
+    pub const ALL: [SkipReason; 4] = [
+        SkipReason::MalformedArguments,
+        SkipReason::InvalidAccountId,
+        SkipReason::MissingEvent,
+        SkipReason::MissingReceipt,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::MalformedArguments => "malformed_arguments",
+            SkipReason::InvalidAccountId => "invalid_account_id",
+            SkipReason::MissingEvent => "missing_event",
+            SkipReason::MissingReceipt => "missing_receipt",
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Indexer {
     latest_block_height: Arc<RwLock<LatestBlockHeight>>,
     last_updated_timestamp: Arc<RwLock<Instant>>,
     latest_block_timestamp_nanosec: Arc<RwLock<Option<u64>>>,
+    skipped_actions: Arc<RwLock<HashMap<SkipReason, u64>>>,
     running_threshold: Duration,
     behind_threshold: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 impl Indexer {
-    pub fn new(latest_block_height: LatestBlockHeight, options: &Options) -> Self {
+    pub fn new(
+        latest_block_height: LatestBlockHeight,
+        options: &Options,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         tracing::info!(
             "creating new indexer, latest block height: {}",
             latest_block_height.block_height
         );
         Self {
             latest_block_height: Arc::new(RwLock::new(latest_block_height)),
-            last_updated_timestamp: Arc::new(RwLock::new(Instant::now())),
+            last_updated_timestamp: Arc::new(RwLock::new(clock.now())),
             latest_block_timestamp_nanosec: Arc::new(RwLock::new(None)),
+            skipped_actions: Arc::new(RwLock::new(HashMap::new())),
             running_threshold: Duration::from_secs(options.running_threshold),
             behind_threshold: Duration::from_secs(options.behind_threshold),
+            clock,
         }
     }
     /// Get the latest block height from the chain.
@@ -69,113 +172,186 @@ impl Indexer {
 
     /// Check whether the indexer is on track with the latest block height from the chain.
     pub async fn is_running(&self) -> bool {
-        self.last_updated_timestamp.read().await.elapsed() <= self.running_threshold
+        let last_updated = *self.last_updated_timestamp.read().await;
+        self.clock.now().saturating_duration_since(last_updated) <= self.running_threshold
     }
 
     /// Check whether the indexer is behind with the latest block height from the chain.
     pub async fn is_behind(&self) -> bool {
-        if let Some(latest_block_timestamp_nanosec) =
-            *self.latest_block_timestamp_nanosec.read().await
-        {
-            is_elapsed_longer_than_timeout(
-                latest_block_timestamp_nanosec / 1_000_000_000,
-                self.behind_threshold.as_millis() as u64,
-            )
-        } else {
-            true
+        match self.latest_block_lag().await {
+            Some(lag) => lag > self.behind_threshold,
+            None => true,
         }
     }
 
-    async fn update_block_height_and_timestamp(
+    /// How long ago the last handled block was produced, `None` before the first block.
+    /// Zero for blocks from the future.
+    pub async fn latest_block_lag(&self) -> Option<Duration> {
+        let block_timestamp_nanosec = (*self.latest_block_timestamp_nanosec.read().await)?;
+        let lag = self
+            .clock
+            .unix_time()
+            .saturating_sub(Duration::from_nanos(block_timestamp_nanosec));
+        Some(lag)
+    }
+
+    /// Number of actions on the contract that were skipped since the node started, by reason
+    pub async fn skipped_actions(&self) -> HashMap<SkipReason, u64> {
+        self.skipped_actions.read().await.clone()
+    }
+
+    async fn record_skip(&self, reason: SkipReason) {
+        metrics::SKIPPED_ACTIONS
+            .with_label_values(&[reason.as_str()])
+            .inc();
+        *self
+            .skipped_actions
+            .write()
+            .await
+            .entry(reason)
+            .or_default() += 1;
+    }
+
+    pub(crate) async fn update_block_height_and_timestamp(
         &self,
         block_height: BlockHeight,
         block_timestamp_nanosec: u64,
     ) {
         tracing::debug!(block_height, "update_block_height_and_timestamp");
-        *self.last_updated_timestamp.write().await = Instant::now();
+        metrics::BLOCKS_PROCESSED.inc();
+        metrics::LATEST_BLOCK_HEIGHT.set(block_height as i64);
+        *self.last_updated_timestamp.write().await = self.clock.now();
         *self.latest_block_timestamp_nanosec.write().await = Some(block_timestamp_nanosec);
         let _val = self.latest_block_height.write().await.set(block_height);
     }
 }
 
-#[derive(Clone, LakeContext)]
+/// The last block the indexer fully processed, persisted so a restart picks up where it left off
+#[derive(Clone)]
+pub struct Checkpoint {
+    tree: sled::Tree,
+}
+
+impl Checkpoint {
+    const KEY: &'static [u8] = b"last_processed_block_height";
+    const RESET_KEY: &'static [u8] = b"applied_reset_from";
+
+    pub fn open(db: &sled::Db) -> anyhow::Result<Self> {
+        let tree = db
+            .open_tree("indexer")
+            .context("failed to open indexer tree")?;
+        Ok(Self { tree })
+    }
+
+    pub fn load(&self) -> anyhow::Result<Option<BlockHeight>> {
+        let Some(value) = self.tree.get(Self::KEY)? else {
+            return Ok(None);
+        };
+        let bytes = value.as_ref().try_into().context("malformed checkpoint")?;
+        Ok(Some(BlockHeight::from_be_bytes(bytes)))
+    }
+
+    /// Record a block as processed, only call this once everything it produced is persisted
+    pub fn save(&self, block_height: BlockHeight) -> anyhow::Result<()> {
+        self.tree.insert(Self::KEY, &block_height.to_be_bytes())?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// Discard the progress to restart from `block_height`, unless that reset was already
+    /// applied. Returns whether the progress was discarded.
+    pub fn reset(&self, block_height: BlockHeight) -> anyhow::Result<bool> {
+        let applied = self.tree.get(Self::RESET_KEY)?;
+        if applied.as_deref() == Some(block_height.to_be_bytes().as_slice()) {
+            return Ok(false);
+        }
+        self.tree.remove(Self::KEY)?;
+        self.tree
+            .insert(Self::RESET_KEY, &block_height.to_be_bytes())?;
+        self.tree.flush()?;
+        Ok(true)
+    }
+
+    /// The block to (re)start the lake from: right after the checkpoint, or `default` without one
+    pub fn start_block_height(&self, default: BlockHeight) -> anyhow::Result<BlockHeight> {
+        Ok(self.load()?.map_or(default, |height| height + 1))
+    }
+}
+
+#[derive(Clone)]
 struct Context {
     contract: AccountId,
     worker: AccountId,
-    queue: Arc<RwLock<RequestQueue>>,
+    queue: RequestQueue,
+    checkpoint: Checkpoint,
+    mirror: ContractMirror,
+    // actions whose receipt was not in their block yet, with the number of attempts so far.
+    // Not persisted, anything lost on a restart is picked up by the startup backfill
+    deferred: Arc<Mutex<Vec<(Action, u32)>>>,
     indexer: Indexer,
+    // cancelled once the supervisor gives up on this run or the node shuts down
+    cancel: CancellationToken,
 }
 
-pub fn is_elapsed_longer_than_timeout(timestamp_sec: u64, timeout: u64) -> bool {
-    if let LocalResult::Single(msg_timestamp) = Utc.timestamp_opt(timestamp_sec as i64, 0) {
-        let timeout = Duration::from_millis(timeout);
-        let now_datetime: DateTime<Utc> = Utc::now();
-        // Calculate the difference in seconds
-        let elapsed_duration = now_datetime.signed_duration_since(msg_timestamp);
-        let timeout = chrono::Duration::seconds(timeout.as_secs() as i64)
-            + chrono::Duration::nanoseconds(timeout.subsec_nanos() as i64);
-        elapsed_duration > timeout
-    } else {
-        false
+async fn handle_block(mut block: Block, ctx: &Context) -> anyhow::Result<()> {
+    if ctx.cancel.is_cancelled() {
+        anyhow::bail!("indexer run was stopped");
     }
-}
-async fn handle_block(
-    mut block: near_lake_primitives::block::Block,
-    ctx: &Context,
-) -> anyhow::Result<()> {
     tracing::debug!(block_height = block.block_height(), "handling block");
-    let mut pending_request = Vec::new();
-    for action in block.actions().cloned().collect::<Vec<_>>() {
-        if action.receiver_id() == ctx.contract {
-            tracing::debug!("got action targeting {}", ctx.contract);
-            let Some(receipt) = block.receipt_by_id(&action.receipt_id()) else {
-                let err = format!(
-                    "indexer unable to find block for receipt_id={}",
-                    action.receipt_id()
-                );
-                tracing::warn!("{err}");
-                anyhow::bail!(err);
-            };
-            let ExecutionStatus::SuccessReceiptId(receipt_id) = receipt.status() else {
+    let mut pending = PendingJobs::default();
+
+    // actions whose receipt was missing from an earlier block come first, then this block's
+    let mut actions: Vec<(Action, u32)> = std::mem::take(&mut *ctx.deferred.lock().await);
+    actions.extend(
+        block
+            .actions()
+            .filter(|action| action.receiver_id() == ctx.contract)
+            .cloned()
+            .map(|action| (action, 0)),
+    );
+
+    for (action, attempts) in actions {
+        tracing::debug!("got action targeting {}", ctx.contract);
+        let Some(receipt) = block.receipt_by_id(&action.receipt_id()) else {
+            if attempts + 1 < MAX_RECEIPT_ATTEMPTS {
+                tracing::debug!(receipt_id = %action.receipt_id(), attempts, "receipt not found, retrying with the next block");
+                ctx.deferred.lock().await.push((action, attempts + 1));
+            } else {
+                tracing::warn!(receipt_id = %action.receipt_id(), "receipt not found, skipping action");
+                ctx.indexer.record_skip(SkipReason::MissingReceipt).await;
+            }
+            continue;
+        };
+        match receipt.status() {
+            // a call that returns a promise still committed its own state changes, the events
+            // it logged are what `handle_action` relies on
+            ExecutionStatus::SuccessValue(_) | ExecutionStatus::SuccessReceiptId(_) => {}
+            ExecutionStatus::Failure(err) => {
+                tracing::debug!(receipt_id = %action.receipt_id(), %err, "ignoring failed receipt");
                 continue;
-            };
-            let Some(function_call) = action.as_function_call() else {
+            }
+            ExecutionStatus::Postponed => {
+                tracing::debug!(receipt_id = %action.receipt_id(), "ignoring postponed receipt");
                 continue;
-            };
-
-            if function_call.method_name() == "add_request" {
-                let arguments =
-                    match serde_json::from_slice::<'_, RequestArguments>(function_call.args()) {
-                        Ok(arguments) => arguments,
-                        Err(err) => {
-                            tracing::warn!("failed to parse arguments: {err}");
-                            continue;
-                        }
-                    };
-                if arguments
-                    .workers
-                    .iter()
-                    .any(|w| ctx.worker == w.parse().unwrap())
-                {
-                    pending_request.push(ModelData {
-                        dataset: arguments.dataset_cid,
-                        compressed_secret_key: arguments.compressed_sk,
-                    });
-                } else {
-                    continue;
-                }
             }
         }
+        if let Err(reason) = handle_action(&action, &receipt.logs(), ctx, &mut pending).await {
+            tracing::warn!(receipt_id = %action.receipt_id(), ?reason, "skipping action");
+            ctx.indexer.record_skip(reason).await;
+        }
     }
     ctx.indexer
         .update_block_height_and_timestamp(block.block_height(), block.header().timestamp_nanosec())
         .await;
-    let mut queue = ctx.queue.write().await;
-
-    for request in pending_request {
-        queue.add_request(request);
+    for request in pending.requests {
+        ctx.queue.add_request(request)?;
     }
-    drop(queue);
+    for inference in pending.inferences {
+        ctx.queue.add_inference(inference)?;
+    }
+    // the queue is flushed at this point, a crash before the checkpoint is saved only
+    // re-delivers this block and the queue ignores requests it already knows
+    ctx.checkpoint.save(block.block_height())?;
 
     let log_indexing_interval = 1000;
     if block.block_height() % log_indexing_interval == 0 {
@@ -189,114 +365,739 @@ async fn handle_block(
     Ok(())
 }
 
-pub fn run(
+/// Jobs found in a block, enqueued once the whole block is handled
+#[derive(Default)]
+struct PendingJobs {
+    requests: Vec<TrainingRequest>,
+    inferences: Vec<InferenceRequest>,
+}
+
+/// Update the mirror and collect jobs for a single successful action on the contract
+async fn handle_action(
+    action: &Action,
+    logs: &[String],
+    ctx: &Context,
+    pending: &mut PendingJobs,
+) -> Result<(), SkipReason> {
+    let Some(function_call) = action.as_function_call() else {
+        return Ok(());
+    };
+    let method = function_call.method_name();
+    let args = function_call.args();
+    let predecessor = action.predecessor_id();
+    match method {
+        "add_request" => {
+            let Some(arguments) = parse_arguments::<RequestArguments>(method, args) else {
+                return Err(SkipReason::MalformedArguments);
+            };
+            let Some(workers) = parse_workers(&arguments.workers) else {
+                return Err(SkipReason::InvalidAccountId);
+            };
+            if !workers.contains(&ctx.worker) {
+                return Ok(());
+            }
+            let Some(event) = event_data::<RequestAddedData>(logs, "request_added") else {
+                return Err(SkipReason::MissingEvent);
+            };
+            ctx.mirror
+                .add_request(event.request_id, workers.clone())
+                .await;
+            pending.requests.push(TrainingRequest {
+                request_id: event.request_id,
+                epochs: arguments.epochs,
+                model_type: arguments.model_type,
+                creator: predecessor,
+                workers,
+                data: ModelData {
+                    dataset: arguments.dataset_cid,
+                    compressed_secret_key: arguments.compressed_sk,
+                },
+            });
+        }
+        "add_inference_request" => {
+            let Some(arguments) = parse_arguments::<InferenceArguments>(method, args) else {
+                return Err(SkipReason::MalformedArguments);
+            };
+            let Some(workers) = parse_workers(&arguments.workers) else {
+                return Err(SkipReason::InvalidAccountId);
+            };
+            if !workers.contains(&ctx.worker) {
+                return Ok(());
+            }
+            let Some(event) = event_data::<RequestAddedData>(logs, "inference_request_added")
+            else {
+                return Err(SkipReason::MissingEvent);
+            };
+            ctx.mirror
+                .add_request(event.request_id, workers.clone())
+                .await;
+            pending.inferences.push(InferenceRequest {
+                request_id: event.request_id,
+                creator: predecessor,
+                workers,
+                model: arguments.model_cid,
+                input: ModelData {
+                    dataset: arguments.input_cid,
+                    compressed_secret_key: arguments.compressed_sk,
+                },
+            });
+        }
+        "complete_request" | "complete_inference_request" => {
+            let Some(arguments) = parse_arguments::<RequestIdArguments>(method, args) else {
+                return Err(SkipReason::MalformedArguments);
+            };
+            ctx.mirror
+                .complete_request(arguments.request_id, predecessor)
+                .await;
+        }
+        "cancel_request" => {
+            let Some(arguments) = parse_arguments::<RequestIdArguments>(method, args) else {
+                return Err(SkipReason::MalformedArguments);
+            };
+            tracing::info!(request_id = arguments.request_id, "request cancelled");
+            ctx.mirror.cancel_request(arguments.request_id).await;
+        }
+        "add_worker" => {
+            let kind = ProposalKind::AddWorker(predecessor.clone());
+            add_proposal(ctx, logs, kind, predecessor).await?;
+        }
+        "propose_remove_worker" => {
+            let Some(arguments) = parse_arguments::<RemoveWorkerArguments>(method, args) else {
+                return Err(SkipReason::MalformedArguments);
+            };
+            let Some(worker) =
+                parse_workers(&[arguments.worker]).and_then(|w| w.into_iter().next())
+            else {
+                return Err(SkipReason::InvalidAccountId);
+            };
+            add_proposal(ctx, logs, ProposalKind::RemoveWorker(worker), predecessor).await?;
+        }
+        "propose_change_base_fee" => {
+            let Some(arguments) = parse_arguments::<BaseFeeArguments>(method, args) else {
+                return Err(SkipReason::MalformedArguments);
+            };
+            let kind = ProposalKind::ChangeBaseFee(arguments.fee);
+            add_proposal(ctx, logs, kind, predecessor).await?;
+        }
+        "propose_change_stake_amount" => {
+            let Some(arguments) = parse_arguments::<StakeAmountArguments>(method, args) else {
+                return Err(SkipReason::MalformedArguments);
+            };
+            let kind = ProposalKind::ChangeStakeAmount(arguments.stake);
+            add_proposal(ctx, logs, kind, predecessor).await?;
+        }
+        "vote" => {
+            let Some(arguments) = parse_arguments::<VoteArguments>(method, args) else {
+                return Err(SkipReason::MalformedArguments);
+            };
+            ctx.mirror
+                .vote(arguments.proposal_id, predecessor, arguments.vote)
+                .await;
+        }
+        "execute_proposal" => {
+            let Some(event) = event_data::<ProposalExecutedData>(logs, "proposal_executed") else {
+                return Err(SkipReason::MissingEvent);
+            };
+            tracing::info!(
+                proposal_id = event.proposal_id,
+                approved = event.approved,
+                "proposal executed"
+            );
+            ctx.mirror
+                .execute_proposal(event.proposal_id, event.approved)
+                .await;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Feed every block of a pull based source through `handle_block`
+async fn index_blocks(mut source: impl BlockSource, ctx: &Context) -> anyhow::Result<()> {
+    while let Some(message) = source.next_block().await? {
+        handle_block(Block::from(message), ctx).await?;
+    }
+    Ok(())
+}
+
+/// Index every block of `source` on the current task, e.g. to replay recorded blocks in tests.
+/// Progress is checkpointed in `db` like it is by [`run`], the returned indexer holds the stats
+/// of the replay.
+pub async fn replay(
+    source: impl BlockSource,
     options: &Options,
     contract_id: &AccountId,
     worker_account_id: &AccountId,
-    queue: &Arc<RwLock<RequestQueue>>,
-    rt: &tokio::runtime::Runtime,
-) -> anyhow::Result<(JoinHandle<anyhow::Result<()>>, Indexer)> {
-    tracing::info!(
-        start_block_height = options.start_block_height,
-        %contract_id,
-        "starting indexer"
-    );
+    queue: &RequestQueue,
+    mirror: &ContractMirror,
+    db: &sled::Db,
+) -> anyhow::Result<Indexer> {
+    let latest_block_height = LatestBlockHeight {
+        account_id: worker_account_id.clone(),
+        block_height: options.start_block_height,
+    };
+    let context = Context {
+        contract: contract_id.clone(),
+        worker: worker_account_id.clone(),
+        queue: queue.clone(),
+        checkpoint: Checkpoint::open(db)?,
+        mirror: mirror.clone(),
+        deferred: Arc::default(),
+        indexer: Indexer::new(latest_block_height, options, Arc::new(SystemClock)),
+        cancel: CancellationToken::new(),
+    };
+    index_blocks(source, &context).await?;
+    Ok(context.indexer)
+}
 
-    let latest_block_height = rt.block_on(async {
-        LatestBlockHeight {
-            account_id: worker_account_id.clone(),
-            block_height: BlockHeight::from(options.start_block_height),
+/// Parse the worker set of a request, `None` if any of the account ids is invalid
+fn parse_workers(workers: &[String]) -> Option<Vec<AccountId>> {
+    match workers.iter().map(|w| w.parse()).collect() {
+        Ok(workers) => Some(workers),
+        Err(err) => {
+            tracing::warn!("failed to parse worker account id: {err}");
+            None
         }
-    });
+    }
+}
+
+/// Parse the JSON arguments of a function call, `None` if they are malformed
+fn parse_arguments<T: DeserializeOwned>(method: &str, args: &[u8]) -> Option<T> {
+    match serde_json::from_slice(args) {
+        Ok(arguments) => Some(arguments),
+        Err(err) => {
+            tracing::warn!("failed to parse {method} arguments: {err}");
+            None
+        }
+    }
+}
 
-    let indexer = Indexer::new(latest_block_height, options);
+/// The data of a contract event logged in the receipt, e.g. the id it assigned to a request
+fn event_data<T: DeserializeOwned>(logs: &[String], event: &str) -> Option<T> {
+    logs.iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .filter_map(|json| serde_json::from_str::<ContractEvent>(json).ok())
+        .find(|e| e.standard == "veilnet" && e.event == event)
+        .and_then(|e| serde_json::from_value(e.data?).ok())
+}
+
+async fn add_proposal(
+    ctx: &Context,
+    logs: &[String],
+    kind: ProposalKind,
+    proposer: AccountId,
+) -> Result<(), SkipReason> {
+    let event = event_data::<ProposalCreatedData>(logs, "proposal_created")
+        .ok_or(SkipReason::MissingEvent)?;
+    tracing::info!(proposal_id = event.proposal_id, ?kind, "proposal created");
+    ctx.mirror
+        .add_proposal(event.proposal_id, kind, proposer)
+        .await;
+    Ok(())
+}
+
+/// Stops the indexer started by [`run`]
+pub struct IndexerHandle {
+    cancel: CancellationToken,
+    task: tokio::task::JoinHandle<anyhow::Result<()>>,
+}
+
+impl IndexerHandle {
+    /// Whether the indexer stopped on its own, e.g. after replaying every recorded block
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stop indexing and wait for the supervisor to wind down
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.cancel.cancel();
+        self.task.await?
+    }
+}
+
+/// Start indexing on the current runtime, supervised so a stalled or failed run is restarted
+/// from the last checkpoint
+pub fn run(
+    options: &Options,
+    contract_id: &AccountId,
+    worker_account_id: &AccountId,
+    queue: &RequestQueue,
+    mirror: &ContractMirror,
+    db: &sled::Db,
+) -> anyhow::Result<(IndexerHandle, Indexer)> {
+    let checkpoint = Checkpoint::open(db)?;
+    if let Some(reset_from) = options.reset_from {
+        if checkpoint.reset(reset_from)? {
+            tracing::error!(reset_from, "discarded saved indexer progress");
+        } else {
+            tracing::info!(
+                reset_from,
+                "reset already applied, keeping saved indexer progress"
+            );
+        }
+    }
+    let default_start = options.reset_from.unwrap_or(options.start_block_height);
+    let start_block_height = checkpoint.start_block_height(default_start)?;
+    tracing::info!(start_block_height, %contract_id, "starting indexer");
+
+    let latest_block_height = LatestBlockHeight {
+        account_id: worker_account_id.clone(),
+        block_height: start_block_height,
+    };
+    let indexer = Indexer::new(latest_block_height, options, Arc::new(SystemClock));
+    let cancel = CancellationToken::new();
     let context = Context {
         contract: contract_id.clone(),
         worker: worker_account_id.clone(),
         queue: queue.clone(),
+        checkpoint,
+        mirror: mirror.clone(),
+        deferred: Arc::default(),
         indexer: indexer.clone(),
+        cancel: cancel.clone(),
     };
-    let options = options.clone();
+    let task = tokio::spawn(supervise(options.clone(), context, default_start));
+    Ok((IndexerHandle { cancel, task }, indexer))
+}
 
-    let join_handle = std::thread::spawn(move || {
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap();
+/// Run the indexer until it is cancelled, restarting it with a backoff whenever it fails to
+/// start, fails or stops making progress
+async fn supervise(
+    options: Options,
+    context: Context,
+    default_start: BlockHeight,
+) -> anyhow::Result<()> {
+    let cancel = context.cancel.clone();
+    let mut restarts: u32 = 0;
+    loop {
+        if restarts > 0 {
+            let latest = context.indexer.latest_block_height().await;
+            tracing::warn!(latest, restarts, "restarting indexer");
+            metrics::INDEXER_RESTARTS.inc();
+        }
 
-        let mut i = 0;
-        loop {
-            if i > 0 {
-                tracing::warn!("indexer is behind, restarting count={i}");
-            }
-            i += 1;
-            let Ok(lake) = rt.block_on(async {
-                let latest = context.indexer.latest_block_height().await;
-                if i > 0 {
-                    tracing::warn!("indexer latest height {latest}, restart count={i}");
+        // every run gets its own token, so a run that is given up on stops handling blocks
+        // even if its task can not be aborted
+        let run_cancel = cancel.child_token();
+        let context = Context {
+            cancel: run_cancel.clone(),
+            ..context.clone()
+        };
+        let outcome = match start_indexing(&options, context.clone(), default_start) {
+            Ok(indexing) => {
+                let mut task = tokio::spawn(indexing);
+                let outcome = tokio::select! {
+                    _ = cancel.cancelled() => None,
+                    outcome = watch_progress(&context.indexer, &mut task, restarts) => outcome,
+                };
+                run_cancel.cancel();
+                if outcome.is_none() {
+                    task.abort();
                 }
-                let mut lake_builder =
-                    LakeBuilder::default().start_block_height(options.start_block_height);
-                match options.chain_id {
-                    ChainId::Mainnet => {
-                        lake_builder = lake_builder.mainnet();
-                    }
-                    ChainId::Testnet => {
-                        lake_builder = lake_builder.testnet();
-                    }
+                outcome
+            }
+            Err(err) => {
+                tracing::error!(?options, ?err, "indexer failed to build");
+                Some(Err(err))
+            }
+        };
+
+        match outcome {
+            _ if cancel.is_cancelled() => {
+                tracing::info!("indexer stopped");
+                return Ok(());
+            }
+            Some(Ok(())) if options.block_source == BlockSourceKind::Directory => {
+                tracing::info!("replayed all recorded blocks");
+                return Ok(());
+            }
+            Some(Ok(())) => {
+                tracing::warn!("indexer finished successfully? -- this should not happen");
+                return Ok(());
+            }
+            Some(Err(err)) => tracing::warn!(?err, "indexer failed"),
+            None => tracing::warn!("indexer stalled"),
+        }
+
+        let delay = jitter(backoff_delay(restarts, 1, 1200));
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                tracing::info!("indexer stopped");
+                return Ok(());
+            }
+            _ = tokio::time::sleep(delay) => {}
+        }
+        restarts = restarts.saturating_add(1);
+    }
+}
+
+/// The future indexing blocks from the configured source, starting after the checkpoint
+fn start_indexing(
+    options: &Options,
+    context: Context,
+    default_start: BlockHeight,
+) -> anyhow::Result<BoxFuture<'static, anyhow::Result<()>>> {
+    // resume after the last processed block rather than from where the node started
+    let start_block_height = context.checkpoint.start_block_height(default_start)?;
+    let indexing: BoxFuture<'static, anyhow::Result<()>> = match options.block_source {
+        BlockSourceKind::Lake => {
+            let mut lake_builder = LakeBuilder::default().start_block_height(start_block_height);
+            match options.chain_id() {
+                ChainId::Mainnet => {
+                    lake_builder = lake_builder.mainnet();
                 }
-                let lake = lake_builder.build()?;
-                anyhow::Ok(lake)
-            }) else {
-                tracing::error!(?options, "indexer failed to build");
-                backoff(i, 10, 3000);
-                continue;
-            };
-            let join_handle = {
-                let context = context.clone();
-                rt.spawn(async move { lake.run_with_context(handle_block, &context)})
-            };
-            let outcome = rt.block_on(async {
-                if i > 0 {
-                    tracing::debug!("giving indexer some time to catch up");
-                    backoff(i, 10, 300);
+                ChainId::Testnet => {
+                    lake_builder = lake_builder.testnet();
                 }
-                while context.indexer.is_running().await {
-                    tokio::time::sleep(Duration::from_secs(60)).await;
-                    if join_handle.is_finished() {
-                        break;
-                    }
+            }
+            let lake = lake_builder.build()?;
+            // the lake drives its own runtime, so it gets a thread of its own rather than a
+            // task. Not a blocking task either: the runtime would wait for it when shutting down.
+            // It only forwards blocks, they are handled by this run on the supervisor's runtime
+            // so aborting the run stops the indexing right away.
+            let (blocks_tx, mut blocks) = tokio::sync::mpsc::channel(1);
+            let (done, result) = tokio::sync::oneshot::channel();
+            std::thread::Builder::new()
+                .name("near-lake".to_string())
+                .spawn(move || {
+                    let forwarder = LakeForwarder { blocks: blocks_tx };
+                    let outcome = lake.run_with_context(forward_lake_block, &forwarder);
+                    let _ = done.send(outcome.map_err(anyhow::Error::from));
+                })
+                .context("failed to spawn the lake thread")?;
+            Box::pin(async move {
+                loop {
+                    let block = tokio::select! {
+                        _ = context.cancel.cancelled() => return Ok(()),
+                        block = blocks.recv() => block,
+                    };
+                    // the lake dropped its sender, so it ended
+                    let Some(block) = block else { break };
+                    handle_block(block, &context).await?;
                 }
+                result.await.context("the lake thread panicked")?
+            })
+        }
+        BlockSourceKind::Rpc => {
+            let source = RpcBlockSource::new(
+                &options.rpc_url,
+                context.contract.as_str(),
+                start_block_height,
+                Duration::from_millis(options.poll_interval_ms),
+            );
+            Box::pin(async move { index_blocks(source, &context).await })
+        }
+        BlockSourceKind::Directory => {
+            let dir = options
+                .blocks_dir
+                .as_deref()
+                .context("the directory block source needs --blocks-dir")?;
+            let source = DirectoryBlockSource::new(dir, start_block_height)?;
+            Box::pin(async move { index_blocks(source, &context).await })
+        }
+    };
+    Ok(indexing)
+}
 
-                // Abort the indexer task if it's still running.
-                if !join_handle.is_finished() {
-                    tracing::debug!("aborting indexer task");
-                    join_handle.abort();
-                }
+/// Hands the blocks of the lake thread to the indexing run
+#[derive(LakeContext)]
+struct LakeForwarder {
+    blocks: tokio::sync::mpsc::Sender<Block>,
+}
 
-                join_handle.await
-            });
-            match outcome {
-                Ok(Ok(())) => {
-                    tracing::warn!("indexer finished successfully? -- this should not happen");
-                    break;
-                }
-                Ok(Err(err)) => {
-                    tracing::warn!(%err, "indexer failed");
-                }
-                Err(err) => {
-                    tracing::warn!(%err, "indexer failed");
+async fn forward_lake_block(block: Block, ctx: &LakeForwarder) -> anyhow::Result<()> {
+    // once the run is aborted the receiver is gone. The lake can't be told to stop, it
+    // carries on with the next block whenever one fails, so every block fails from then on
+    ctx.blocks
+        .send(block)
+        .await
+        .map_err(|_| anyhow::anyhow!("the indexing run stopped"))
+}
+
+/// Wait for an indexing run to end, `None` once it stopped making progress.
+/// After a restart the run gets some time to catch up before it is checked.
+async fn watch_progress(
+    indexer: &Indexer,
+    task: &mut tokio::task::JoinHandle<anyhow::Result<()>>,
+    restarts: u32,
+) -> Option<anyhow::Result<()>> {
+    let grace = if restarts > 0 {
+        backoff_delay(restarts, 10, 300)
+    } else {
+        Duration::ZERO
+    };
+    let checks_start = tokio::time::Instant::now() + grace;
+    loop {
+        tokio::select! {
+            outcome = &mut *task => {
+                return Some(outcome.map_err(anyhow::Error::from).and_then(|result| result));
+            }
+            _ = tokio::time::sleep(STALL_CHECK_INTERVAL) => {
+                if tokio::time::Instant::now() >= checks_start && !indexer.is_running().await {
+                    return None;
                 }
             }
-            backoff(i, 1, 1200)
         }
-        Ok(())
-    });
-    Ok((join_handle, indexer))
+    }
+}
+
+/// Exponential backoff of `multiplier * 2^i` seconds, capped at `max` seconds
+fn backoff_delay(i: u32, multiplier: u32, max: u64) -> Duration {
+    let delay = 2u64
+        .checked_pow(i)
+        .map_or(u64::MAX, |base| base.saturating_mul(multiplier as u64));
+    Duration::from_secs(delay.min(max))
 }
 
-fn backoff(i: u32, multiplier: u32, max: u64) {
-    // Exponential backoff with max delay of max seconds
-    let delay: u64 = std::cmp::min(2u64.pow(i).mul(multiplier as u64), max);
-    std::thread::sleep(std::time::Duration::from_secs(delay));
+/// Somewhere between half of `delay` and all of it, so nodes restarted together spread out
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::ModelType;
+    use clap::Parser;
+    use std::sync::Mutex as StdMutex;
+
+    /// A clock that only moves when the test advances it
+    struct ManualClock {
+        start: Instant,
+        start_unix_time: Duration,
+        elapsed: StdMutex<Duration>,
+    }
+
+    impl ManualClock {
+        fn new(start_unix_time: Duration) -> Arc<Self> {
+            Arc::new(Self {
+                start: Instant::now(),
+                start_unix_time,
+                elapsed: StdMutex::new(Duration::ZERO),
+            })
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.elapsed.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+
+        fn unix_time(&self) -> Duration {
+            self.start_unix_time + *self.elapsed.lock().unwrap()
+        }
+    }
+
+    const START: Duration = Duration::from_secs(1_700_000_000);
+
+    fn indexer(clock: Arc<ManualClock>) -> Indexer {
+        let options = Options::try_parse_from([
+            "indexer",
+            "--behind-threshold",
+            "200",
+            "--running-threshold",
+            "300",
+        ])
+        .unwrap();
+        let latest_block_height = LatestBlockHeight {
+            account_id: "worker.test.near".parse().unwrap(),
+            block_height: 0,
+        };
+        Indexer::new(latest_block_height, &options, clock)
+    }
+
+    #[tokio::test]
+    async fn is_running_until_no_block_for_the_running_threshold() {
+        let clock = ManualClock::new(START);
+        let indexer = indexer(clock.clone());
+        assert!(indexer.is_running().await);
+
+        clock.advance(Duration::from_secs(300));
+        assert!(indexer.is_running().await);
+        clock.advance(Duration::from_secs(1));
+        assert!(!indexer.is_running().await);
+
+        indexer
+            .update_block_height_and_timestamp(1, START.as_nanos() as u64)
+            .await;
+        assert!(indexer.is_running().await);
+    }
+
+    #[tokio::test]
+    async fn latest_block_lag_grows_until_the_next_block() {
+        let clock = ManualClock::new(START);
+        let indexer = indexer(clock.clone());
+        assert_eq!(indexer.latest_block_lag().await, None);
+
+        let block_timestamp = START - Duration::from_secs(2);
+        indexer
+            .update_block_height_and_timestamp(1, block_timestamp.as_nanos() as u64)
+            .await;
+        assert_eq!(
+            indexer.latest_block_lag().await,
+            Some(Duration::from_secs(2))
+        );
+        // no block for a while, the lag keeps growing
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(
+            indexer.latest_block_lag().await,
+            Some(Duration::from_secs(62))
+        );
+
+        indexer
+            .update_block_height_and_timestamp(2, START.as_nanos() as u64 + 60_000_000_000)
+            .await;
+        assert_eq!(indexer.latest_block_lag().await, Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn is_behind_compares_block_age_in_seconds() {
+        let clock = ManualClock::new(START);
+        let indexer = indexer(clock.clone());
+        // no block handled yet
+        assert!(indexer.is_behind().await);
+
+        let block_timestamp = START - Duration::from_secs(150);
+        indexer
+            .update_block_height_and_timestamp(1, block_timestamp.as_nanos() as u64)
+            .await;
+        assert!(!indexer.is_behind().await);
+
+        clock.advance(Duration::from_secs(50));
+        assert!(!indexer.is_behind().await);
+        clock.advance(Duration::from_secs(1));
+        assert!(indexer.is_behind().await);
+    }
+
+    #[tokio::test]
+    async fn blocks_from_the_future_are_not_behind() {
+        let clock = ManualClock::new(START);
+        let indexer = indexer(clock);
+        let block_timestamp = START + Duration::from_secs(10);
+        indexer
+            .update_block_height_and_timestamp(1, block_timestamp.as_nanos() as u64)
+            .await;
+        assert!(!indexer.is_behind().await);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        assert_eq!(backoff_delay(0, 10, 300), Duration::from_secs(10));
+        assert_eq!(backoff_delay(1, 10, 300), Duration::from_secs(20));
+        assert_eq!(backoff_delay(4, 10, 300), Duration::from_secs(160));
+        assert_eq!(backoff_delay(5, 10, 300), Duration::from_secs(300));
+        assert_eq!(backoff_delay(3, 1, 1200), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn jitter_stays_within_half_of_the_delay() {
+        let delay = Duration::from_secs(100);
+        for _ in 0..100 {
+            let jittered = jitter(delay);
+            assert!(jittered >= delay / 2 && jittered <= delay);
+        }
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        assert_eq!(backoff_delay(63, 10, 300), Duration::from_secs(300));
+        assert_eq!(backoff_delay(64, 1, 1200), Duration::from_secs(1200));
+        assert_eq!(backoff_delay(u32::MAX, 1, 1200), Duration::from_secs(1200));
+    }
+
+    #[test]
+    fn reset_is_applied_once() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let checkpoint = Checkpoint::open(&db).unwrap();
+        checkpoint.save(500).unwrap();
+
+        assert!(checkpoint.reset(100).unwrap());
+        assert_eq!(checkpoint.start_block_height(100).unwrap(), 100);
+        checkpoint.save(120).unwrap();
+        // the option is still set on the next start
+        assert!(!checkpoint.reset(100).unwrap());
+        assert_eq!(checkpoint.start_block_height(100).unwrap(), 121);
+        assert!(checkpoint.reset(110).unwrap());
+        assert_eq!(checkpoint.load().unwrap(), None);
+    }
+
+    #[test]
+    fn chain_can_still_be_given_as_a_subcommand() {
+        let options = Options::try_parse_from(["indexer", "mainnet"]).unwrap();
+        assert_eq!(options.chain_id(), ChainId::Mainnet);
+        let options = Options::try_parse_from(["indexer", "--chain-id", "mainnet"]).unwrap();
+        assert_eq!(options.chain_id(), ChainId::Mainnet);
+        let options = Options::try_parse_from(["indexer"]).unwrap();
+        assert_eq!(options.chain_id(), ChainId::Testnet);
+    }
+
+    #[test]
+    fn add_request_needs_a_model_type() {
+        let args = br#"{"epochs":2,"dataset_cid":"Qm","compressed_sk":[],"workers":[]}"#;
+        assert!(parse_arguments::<RequestArguments>("add_request", args).is_none());
+
+        let args = br#"{"epochs":2,"dataset_cid":"Qm","compressed_sk":[],"workers":[],"model_type":"Perceptron"}"#;
+        let arguments = parse_arguments::<RequestArguments>("add_request", args).unwrap();
+        assert_eq!(arguments.model_type, ModelType::Perceptron);
+    }
+
+    /// Start indexing with `args` against a temporary database
+    fn run_with(args: &[&str]) -> (IndexerHandle, sled::Db) {
+        let options = Options::try_parse_from(["indexer"].iter().chain(args)).unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let (handle, _) = run(
+            &options,
+            &"contract.test.near".parse().unwrap(),
+            &"worker.test.near".parse().unwrap(),
+            &RequestQueue::open(&db).unwrap(),
+            &ContractMirror::default(),
+            &db,
+        )
+        .unwrap();
+        (handle, db)
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_a_run_waiting_for_blocks() {
+        // an RPC node that never answers, so the run waits for its first block
+        let router = axum::Router::new().route(
+            "/",
+            axum::routing::post(|| std::future::pending::<String>()),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let (handle, _db) = run_with(&["--block-source", "rpc", "--rpc-url", &url]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!handle.is_finished());
+        tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .expect("the supervisor did not stop")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_the_supervisor_while_it_backs_off() {
+        // the run fails to build, so the supervisor waits before trying again
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let (handle, _db) = run_with(&[
+            "--block-source",
+            "directory",
+            "--blocks-dir",
+            missing.to_str().unwrap(),
+        ]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!handle.is_finished());
+        tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .expect("the supervisor did not stop")
+            .unwrap();
+    }
 }
@@ -1,70 +1,444 @@
-use core::time;
-use std::sync::Arc;
-use std::time::Instant;
-
-use anyhow::Context;
-use anyhow::Ok;
-use anyhow::Result;
-use futures::stream::StreamExt;
-use ipfs_api::IpfsApi;
-use ipfs_api::IpfsClient;
-use near_crypto::vrf::Value;
+//! Client for the Kubo RPC API (`/api/v0`) of a local or remote IPFS node, or of a hosted
+//! pinning service that exposes it.
+//!
+//! The API is called over reqwest rather than through the `ipfs-api` crate: that client only
+//! does basic auth, can't put a timeout on a request or tell which failures are worth a retry,
+//! and brings a second HTTP stack (hyper 0.14) next to the one every other client here uses.
+//! The tests below run it against a mock Kubo node.
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Body, RequestBuilder, Response, Url};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::cid::{Cid, CidExt, FileBlock};
+use crate::metrics;
+use crate::retry::{RetryPolicy, StatusError};
+use crate::storage::{self, BlobStore};
+
 #[derive(Clone)]
 pub struct IpfsHandler {
-    client: IpfsClient,
-    download_dir: String,
+    http: reqwest::Client,
+    api_url: Url,
+    auth: Option<Auth>,
+    retry: RetryPolicy,
 }
 
 #[derive(Debug, Clone, clap::Parser)]
 #[group(id = "ipfs-options")]
 pub struct Options {
-    #[clap(long, env("IPFS_DOWNLOAD_DIR"), default_value = "./datasets/")]
-    pub download_dir: String,
+    /// The Kubo RPC API of the IPFS node or pinning service.
+    #[clap(long, env("IPFS_API_URL"), default_value = "http://localhost:5001")]
+    pub ipfs_api_url: Url,
+
+    /// Username for basic auth, e.g. the project id of a pinning service.
+    #[clap(long, env("IPFS_USERNAME"), conflicts_with = "ipfs_bearer_token")]
+    pub ipfs_username: Option<String>,
+
+    /// Password for basic auth, only used together with the username.
+    #[clap(long, env("IPFS_PASSWORD"), requires = "ipfs_username")]
+    pub ipfs_password: Option<String>,
+
+    /// Token sent as `Authorization: Bearer <token>`.
+    #[clap(long, env("IPFS_BEARER_TOKEN"))]
+    pub ipfs_bearer_token: Option<String>,
+
+    /// The threshold in seconds for connecting to the API and for a request to go without any
+    /// data being received.
+    #[clap(long, env("IPFS_TIMEOUT"), default_value = "60")]
+    pub ipfs_timeout: u64,
+
+    /// How many times a request that failed with a network error, a timeout or a 5xx/429
+    /// response is retried.
+    #[clap(long, env("IPFS_MAX_RETRIES"), default_value = "3")]
+    pub ipfs_max_retries: u32,
+
+    /// Delay in milliseconds before the first retry, doubled for every further one.
+    #[clap(long, env("IPFS_RETRY_BACKOFF_MS"), default_value = "500")]
+    pub ipfs_retry_backoff_ms: u64,
+}
+
+#[derive(Clone)]
+enum Auth {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AddResponse {
+    hash: String,
 }
 
 impl IpfsHandler {
-    pub fn new(download_path: &str) -> Result<Self> {
-        let client = IpfsClient::default();
+    pub fn new(options: &Options) -> Result<Self> {
+        let timeout = Duration::from_secs(options.ipfs_timeout);
+        let http = reqwest::Client::builder()
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .build()
+            .context("Failed to build IPFS HTTP client")?;
+
+        let auth = match (&options.ipfs_username, &options.ipfs_bearer_token) {
+            (Some(username), _) => Some(Auth::Basic {
+                username: username.clone(),
+                password: options.ipfs_password.clone(),
+            }),
+            (None, Some(token)) => Some(Auth::Bearer(token.clone())),
+            (None, None) => None,
+        };
 
         Ok(Self {
-            client,
-            download_dir: download_path.to_string(),
+            http,
+            api_url: storage::base_url(&options.ipfs_api_url),
+            auth,
+            retry: RetryPolicy {
+                max_retries: options.ipfs_max_retries,
+                backoff: Duration::from_millis(options.ipfs_retry_backoff_ms),
+            },
         })
     }
 
-    /// Fetch a file from IPFS and save it locally
-    pub async fn fetch_file(&self, cid: &str, filename: &str) -> Result<String> {
-        let path = format!("{}/{}", self.download_dir, filename);
+    /// Fetch a single block and check it against its CID
+    async fn fetch_block(&self, cid: &Cid) -> Result<Bytes> {
+        if let Some(block) = cid.inline_block() {
+            return Ok(Bytes::copy_from_slice(block));
+        }
+        let arg = cid.to_string();
+        let arg = arg.as_str();
+        let block = self
+            .with_retries("block/get", || async move {
+                let response = self.post("block/get", &[("arg", arg)], None).await?;
+                Ok(response.bytes().await?)
+            })
+            .await?;
+        metrics::IPFS_BYTES_FETCHED.inc_by(block.len() as u64);
+        cid.verify(&block)?;
+        Ok(block)
+    }
+
+    /// Upload `len` bytes, `body` is called again for every retry
+    async fn add<F, Fut>(&self, len: u64, body: F) -> Result<String>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Body>>,
+    {
+        let body = &body;
+        let response: AddResponse = self
+            .with_retries("add", || async move {
+                let form = Form::new().part("file", Part::stream_with_length(body().await?, len));
+                let response = self.post("add", &[], Some(form)).await?;
+                response
+                    .json()
+                    .await
+                    .context("Failed to parse IPFS add response")
+            })
+            .await?;
+        metrics::IPFS_BYTES_PUBLISHED.inc_by(len);
+
+        Ok(response.hash)
+    }
+
+    /// A single call to an `/api/v0` endpoint, every endpoint of the Kubo RPC API is a POST
+    async fn post(
+        &self,
+        endpoint: &str,
+        query: &[(&str, &str)],
+        form: Option<Form>,
+    ) -> Result<Response> {
+        let url = self.api_url.join(&format!("api/v0/{endpoint}"))?;
+        let mut request = self.authorize(self.http.post(url).query(query));
+        if let Some(form) = form {
+            request = request.multipart(form);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(StatusError {
+                api: "IPFS API",
+                status,
+                body,
+            }
+            .into());
+        }
+        Ok(response)
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.auth {
+            Some(Auth::Basic { username, password }) => {
+                request.basic_auth(username, password.as_ref())
+            }
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn with_retries<T, F, Fut>(&self, endpoint: &str, call: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.retry.run(&format!("IPFS {endpoint}"), call).await
+    }
+}
 
-        tokio::fs::create_dir_all(&self.download_dir)
+impl BlobStore for IpfsHandler {
+    async fn put_bytes(&self, data: Bytes) -> Result<String> {
+        let len = data.len() as u64;
+        // retries share the buffer instead of copying it
+        let data = &data;
+        self.add(len, || async move { Ok(Body::from(data.clone())) })
             .await
-            .context("Failed to create download directory")?;
+    }
 
-        let mut file = tokio::fs::File::create(&path)
+    async fn put_file(&self, path: &Path) -> Result<String> {
+        let len = tokio::fs::metadata(path)
             .await
-            .context("Failed to create file")?;
+            .context("Failed to read file metadata")?
+            .len();
+        self.add(len, || async move {
+            let file = tokio::fs::File::open(path)
+                .await
+                .context("Failed to open file")?;
+            Ok(Body::wrap_stream(ReaderStream::new(file)))
+        })
+        .await
+    }
 
-        let mut response = self.client.cat(cid);
+    /// The file is assembled block by block, each checked against its own CID, in a temporary
+    /// file that is renamed to `dest` only when all of them check out.
+    async fn get(&self, id: &str, dest: &Path) -> Result<()> {
+        let root = Cid::parse(id)?;
+        let (partial, mut file) = storage::partial_file(storage::parent_dir(dest))?;
 
-        while let Some(chunk) = response.next().await {
-            let chunk = chunk.context("Failed to get chunk from IPFS")?;
-            tokio::io::AsyncWriteExt::write_all(&mut file, &chunk)
+        // blocks still to be written, the next one last
+        let mut pending = vec![root];
+        while let Some(cid) = pending.pop() {
+            let block = self.fetch_block(&cid).await?;
+            let FileBlock { data, links } = cid.decode_file_block(&block)?;
+            file.write_all(&data)
                 .await
                 .context("Failed to write chunk to file")?;
+            pending.extend(links.into_iter().rev());
         }
-        Ok(path)
+        file.flush().await.context("Failed to write file")?;
+        drop(file);
+
+        storage::persist(partial, dest)
     }
-    /// Publish model JSON data to IPFS
-    pub async fn publish_json(&self, data: Value) -> Result<String> {
-        let json_str = serde_json::to_string(&data).context("Failed to serialize JSON")?;
 
-        // this is  memory but for some reason ipfs requires to have 'static data upload only
-        let response = self.client.add_async(to_static_bytes(json_str)).await?;
+    async fn pin(&self, id: &str) -> Result<()> {
+        let query = &[("arg", id)];
+        self.with_retries("pin/add", || self.post("pin/add", query, None))
+            .await?;
+        Ok(())
+    }
 
-        Ok(response.hash)
+    async fn unpin(&self, id: &str) -> Result<()> {
+        let query = &[("arg", id)];
+        self.with_retries("pin/rm", || self.post("pin/rm", query, None))
+            .await?;
+        Ok(())
+    }
+
+    /// Checked once without retrying
+    async fn is_reachable(&self) -> bool {
+        self.post("version", &[], None).await.is_ok()
     }
 }
 
-fn to_static_bytes(s: String) -> &'static [u8] {
-    s.leak().as_bytes()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use axum::extract::{Path as UrlPath, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use clap::Parser;
+    use serde_json::json;
+
+    const HELLO_CID: &str = "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e";
+
+    /// A call to the mock Kubo node
+    struct Call {
+        endpoint: String,
+        authorization: Option<String>,
+    }
+
+    /// What a mock Kubo node was asked, and the statuses it answers the next calls with
+    #[derive(Clone, Default)]
+    struct Kubo {
+        calls: Arc<Mutex<Vec<Call>>>,
+        statuses: Arc<Mutex<VecDeque<StatusCode>>>,
+    }
+
+    impl Kubo {
+        /// Answer the next calls with `statuses`, then with 200
+        fn answer(&self, statuses: &[StatusCode]) {
+            self.statuses.lock().unwrap().extend(statuses);
+        }
+
+        fn endpoints(&self) -> Vec<String> {
+            let calls = self.calls.lock().unwrap();
+            calls.iter().map(|call| call.endpoint.clone()).collect()
+        }
+
+        fn authorizations(&self) -> Vec<Option<String>> {
+            let calls = self.calls.lock().unwrap();
+            calls
+                .iter()
+                .map(|call| call.authorization.clone())
+                .collect()
+        }
+    }
+
+    /// A Kubo RPC API under `/kubo` that stores nothing but "hello world"
+    async fn kubo() -> (String, Kubo) {
+        async fn call(
+            State(kubo): State<Kubo>,
+            UrlPath(endpoint): UrlPath<String>,
+            headers: HeaderMap,
+        ) -> axum::response::Response {
+            let authorization = headers
+                .get("authorization")
+                .map(|value| value.to_str().unwrap().to_string());
+            kubo.calls.lock().unwrap().push(Call {
+                endpoint: endpoint.clone(),
+                authorization,
+            });
+            let status = kubo.statuses.lock().unwrap().pop_front();
+            if let Some(status) = status.filter(|status| !status.is_success()) {
+                return (status, "mock error").into_response();
+            }
+            match endpoint.as_str() {
+                "add" => Json(json!({ "Name": "file", "Hash": HELLO_CID })).into_response(),
+                "block/get" => "hello world".into_response(),
+                "version" => Json(json!({ "Version": "0.32.0" })).into_response(),
+                _ => Json(json!({})).into_response(),
+            }
+        }
+
+        let kubo = Kubo::default();
+        let router = Router::new()
+            .route("/kubo/api/v0/*endpoint", post(call))
+            .with_state(kubo.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        (format!("http://{addr}/kubo"), kubo)
+    }
+
+    fn handler(api_url: &str, args: &[&str]) -> IpfsHandler {
+        let options = Options::parse_from(["node", "--ipfs-api-url", api_url].iter().chain(args));
+        IpfsHandler::new(&options).unwrap()
+    }
+
+    #[tokio::test]
+    async fn calls_the_api_below_the_url_with_or_without_a_trailing_slash() {
+        let (url, kubo) = kubo().await;
+        assert!(handler(&url, &[]).is_reachable().await);
+        assert!(handler(&format!("{url}/"), &[]).is_reachable().await);
+        assert_eq!(kubo.endpoints(), ["version", "version"]);
+    }
+
+    #[tokio::test]
+    async fn adds_and_gets_files() {
+        let (url, kubo) = kubo().await;
+        let ipfs = handler(&url, &[]);
+        let cid = ipfs.put_bytes("hello world".into()).await.unwrap();
+        assert_eq!(cid, HELLO_CID);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("hello.txt");
+        ipfs.get(&cid, &dest).await.unwrap();
+        assert_eq!(std::fs::read(dest).unwrap(), b"hello world");
+        assert_eq!(kubo.endpoints(), ["add", "block/get"]);
+    }
+
+    #[tokio::test]
+    async fn sends_no_credentials_unless_configured() {
+        let (url, kubo) = kubo().await;
+        handler(&url, &[]).pin(HELLO_CID).await.unwrap();
+        assert_eq!(kubo.authorizations(), [None]);
+    }
+
+    #[tokio::test]
+    async fn sends_basic_auth() {
+        let (url, kubo) = kubo().await;
+        let args = ["--ipfs-username", "project", "--ipfs-password", "secret"];
+        handler(&url, &args).pin(HELLO_CID).await.unwrap();
+        handler(&url, &["--ipfs-username", "project"])
+            .pin(HELLO_CID)
+            .await
+            .unwrap();
+        assert_eq!(
+            kubo.authorizations(),
+            [
+                // base64 of "project:secret" and of "project:"
+                Some("Basic cHJvamVjdDpzZWNyZXQ=".to_string()),
+                Some("Basic cHJvamVjdDo=".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_a_bearer_token() {
+        let (url, kubo) = kubo().await;
+        handler(&url, &["--ipfs-bearer-token", "token"])
+            .pin(HELLO_CID)
+            .await
+            .unwrap();
+        assert_eq!(kubo.authorizations(), [Some("Bearer token".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_with_a_growing_delay() {
+        let (url, kubo) = kubo().await;
+        kubo.answer(&[
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::TOO_MANY_REQUESTS,
+        ]);
+        let ipfs = handler(&url, &["--ipfs-retry-backoff-ms", "50"]);
+        let start = Instant::now();
+        ipfs.pin(HELLO_CID).await.unwrap();
+        // 50ms before the first retry and 100ms before the second
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(kubo.endpoints(), ["pin/add", "pin/add", "pin/add"]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_max_retries() {
+        let (url, kubo) = kubo().await;
+        kubo.answer(&[StatusCode::INTERNAL_SERVER_ERROR; 4]);
+        let args = ["--ipfs-max-retries", "2", "--ipfs-retry-backoff-ms", "1"];
+        let err = handler(&url, &args).unpin(HELLO_CID).await.unwrap_err();
+        assert_eq!(err.to_string(), "IPFS pin/rm failed after 3 attempts");
+        assert_eq!(kubo.endpoints().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (url, kubo) = kubo().await;
+        kubo.answer(&[StatusCode::BAD_REQUEST]);
+        let err = handler(&url, &[]).pin(HELLO_CID).await.unwrap_err();
+        assert!(!crate::retry::is_transient(&err));
+        assert_eq!(kubo.endpoints(), ["pin/add"]);
+    }
 }
@@ -0,0 +1,235 @@
+//! A task owning the blob store client, driven by [`IpfsMessage`]s so training jobs and the
+//! indexer share one client and one limit on concurrent transfers.
+//!
+//! Requests travel over a bounded channel and are answered on a oneshot channel each. The task
+//! only takes a request off the channel once a transfer slot is free, so a full channel makes
+//! senders wait instead of piling up work.
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use bytes::Bytes;
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::storage::{BlobStore, Storage};
+use crate::types::{IpfsMessage, IpfsResponse};
+
+#[derive(Debug, Clone, clap::Parser)]
+#[group(id = "ipfs-service-options")]
+pub struct Options {
+    /// How many requests wait for a transfer slot before senders are made to wait.
+    #[clap(long, env("IPFS_QUEUE_SIZE"), default_value = "64")]
+    pub ipfs_queue_size: usize,
+
+    /// How many fetches and publishes run at the same time.
+    #[clap(long, env("IPFS_MAX_CONCURRENT_TRANSFERS"), default_value = "4")]
+    pub ipfs_max_concurrent_transfers: usize,
+}
+
+struct Request {
+    message: IpfsMessage,
+    reply: oneshot::Sender<anyhow::Result<IpfsResponse>>,
+}
+
+/// Sends requests to the service, cheap to clone
+#[derive(Clone)]
+pub struct IpfsService {
+    sender: mpsc::Sender<Request>,
+}
+
+pub struct IpfsServiceHandle {
+    sender: mpsc::Sender<Request>,
+    task: JoinHandle<()>,
+}
+
+impl IpfsServiceHandle {
+    /// Answer the requests sent so far, then stop
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        let (reply, _) = oneshot::channel();
+        // the service is gone already if every sender was dropped
+        let _ = self
+            .sender
+            .send(Request {
+                message: IpfsMessage::ShutDown,
+                reply,
+            })
+            .await;
+        Ok(self.task.await?)
+    }
+}
+
+impl IpfsService {
+    /// Send `message` and wait for its response, waits for room in the queue first
+    pub async fn request(&self, message: IpfsMessage) -> anyhow::Result<IpfsResponse> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(Request { message, reply })
+            .await
+            .map_err(|_| anyhow::anyhow!("IPFS service stopped"))?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("IPFS service stopped before answering"))?
+    }
+
+    /// Fetch `cid` into the download directory as `filename`, returns its path
+    pub async fn fetch_file(&self, cid: &str, filename: &str) -> anyhow::Result<PathBuf> {
+        let message = IpfsMessage::FetchFile {
+            cid: cid.to_string(),
+            filename: filename.to_string(),
+        };
+        match self.request(message).await? {
+            IpfsResponse::FileFetched { path } => Ok(path.into()),
+            response => anyhow::bail!("unexpected response to FetchFile: {response:?}"),
+        }
+    }
+
+    /// Publish JSON data, returns its content id
+    pub async fn publish_json(&self, data: Value) -> anyhow::Result<String> {
+        match self.request(IpfsMessage::PublishJson { data }).await? {
+            IpfsResponse::JsonPublished { cid } => Ok(cid),
+            response => anyhow::bail!("unexpected response to PublishJson: {response:?}"),
+        }
+    }
+
+    /// Publish a binary blob, returns its content id
+    pub async fn publish_bytes(&self, data: Bytes) -> anyhow::Result<String> {
+        match self.request(IpfsMessage::PublishBytes { data }).await? {
+            IpfsResponse::BytesPublished { cid } => Ok(cid),
+            response => anyhow::bail!("unexpected response to PublishBytes: {response:?}"),
+        }
+    }
+
+    /// Publish the file at `path`, returns its content id
+    pub async fn publish_file(&self, path: &Path) -> anyhow::Result<String> {
+        let path = path
+            .to_str()
+            .with_context(|| format!("path {path:?} is not valid UTF-8"))?
+            .to_string();
+        match self.request(IpfsMessage::PublishFile { path }).await? {
+            IpfsResponse::FilePublished { cid } => Ok(cid),
+            response => anyhow::bail!("unexpected response to PublishFile: {response:?}"),
+        }
+    }
+
+    /// Pin `cid` in the blob store
+    pub async fn pin(&self, cid: &str) -> anyhow::Result<()> {
+        let message = IpfsMessage::Pin {
+            cid: cid.to_string(),
+        };
+        match self.request(message).await? {
+            IpfsResponse::Pinned => Ok(()),
+            response => anyhow::bail!("unexpected response to Pin: {response:?}"),
+        }
+    }
+}
+
+/// Start the service on the current runtime, fetched files are saved in `download_dir`
+pub fn run(
+    store: Storage,
+    download_dir: &Path,
+    options: &Options,
+) -> (IpfsServiceHandle, IpfsService) {
+    let (sender, receiver) = mpsc::channel(options.ipfs_queue_size.max(1));
+    let slots = Arc::new(Semaphore::new(options.ipfs_max_concurrent_transfers.max(1)));
+    let task = tokio::spawn(serve(store, download_dir.to_path_buf(), receiver, slots));
+    let handle = IpfsServiceHandle {
+        sender: sender.clone(),
+        task,
+    };
+    (handle, IpfsService { sender })
+}
+
+async fn serve(
+    store: Storage,
+    download_dir: PathBuf,
+    mut receiver: mpsc::Receiver<Request>,
+    slots: Arc<Semaphore>,
+) {
+    tracing::info!(dir = %download_dir.display(), "starting IPFS service");
+    let mut transfers = JoinSet::new();
+    loop {
+        let slot = slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        // finished transfers only need to be reaped
+        while transfers.try_join_next().is_some() {}
+        let Some(Request { message, reply }) = receiver.recv().await else {
+            break;
+        };
+        let store = store.clone();
+        let download_dir = download_dir.clone();
+        match message {
+            IpfsMessage::FetchFile { cid, filename } => {
+                transfers.spawn(async move {
+                    let result = fetch_file(&store, &download_dir, &cid, &filename).await;
+                    if let Err(err) = &result {
+                        tracing::warn!(cid, ?err, "failed to fetch file");
+                    }
+                    let _ = reply.send(result);
+                    drop(slot);
+                });
+            }
+            IpfsMessage::PublishJson { data } => {
+                transfers.spawn(async move {
+                    let result = store.put_json(&data).await;
+                    let _ = reply.send(result.map(|cid| IpfsResponse::JsonPublished { cid }));
+                    drop(slot);
+                });
+            }
+            IpfsMessage::PublishBytes { data } => {
+                transfers.spawn(async move {
+                    let result = store.put_bytes(data).await;
+                    let _ = reply.send(result.map(|cid| IpfsResponse::BytesPublished { cid }));
+                    drop(slot);
+                });
+            }
+            IpfsMessage::PublishFile { path } => {
+                transfers.spawn(async move {
+                    let result = store.put_file(Path::new(&path)).await;
+                    let _ = reply.send(result.map(|cid| IpfsResponse::FilePublished { cid }));
+                    drop(slot);
+                });
+            }
+            IpfsMessage::Pin { cid } => {
+                transfers.spawn(async move {
+                    let result = store.pin(&cid).await;
+                    let _ = reply.send(result.map(|()| IpfsResponse::Pinned));
+                    drop(slot);
+                });
+            }
+            IpfsMessage::ShutDown => break,
+        }
+    }
+
+    // requests sent after the shutdown are answered with an error when their reply is dropped
+    receiver.close();
+    tracing::info!(
+        in_progress = transfers.len(),
+        "stopping IPFS service, finishing transfers in progress"
+    );
+    while transfers.join_next().await.is_some() {}
+    tracing::info!("IPFS service stopped");
+}
+
+async fn fetch_file(
+    store: &Storage,
+    download_dir: &Path,
+    cid: &str,
+    filename: &str,
+) -> anyhow::Result<IpfsResponse> {
+    // the file has to end up in the download directory
+    if Path::new(filename).file_name() != Some(filename.as_ref()) {
+        anyhow::bail!("invalid file name {filename:?}");
+    }
+    let path = download_dir.join(filename);
+    store.get(cid, &path).await?;
+    let path = path
+        .into_os_string()
+        .into_string()
+        .map_err(|path| anyhow::anyhow!("path {path:?} is not valid UTF-8"))?;
+    Ok(IpfsResponse::FileFetched { path })
+}
@@ -1,5 +1,22 @@
+pub mod backfill;
+pub mod block_source;
+pub mod cache;
+pub mod cid;
+pub mod cli;
+pub mod clock;
+pub mod config;
 pub mod indexer;
 pub mod worker;
 pub mod types;
 pub mod ipfs;
-pub mod model;
\ No newline at end of file
+pub mod ipfs_service;
+pub mod metrics;
+pub mod mirror;
+pub mod model;
+pub mod pinning;
+pub mod queue;
+pub mod retry;
+pub mod s3;
+pub mod storage;
+pub mod web;
+pub mod dataset;
\ No newline at end of file
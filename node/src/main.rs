@@ -0,0 +1,11 @@
+use clap::Parser;
+use node::cli::Cli;
+use tracing_subscriber::EnvFilter;
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    node::cli::run(Cli::parse())
+}
@@ -0,0 +1,140 @@
+//! Prometheus metrics of the node, exported on `/metrics` by the web server.
+//!
+//! Every metric is registered in the default registry by [`register`] when the node starts, so
+//! the first scrape already exports all of them.
+use std::sync::LazyLock;
+
+use crate::indexer::SkipReason;
+
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    TextEncoder,
+};
+
+pub static LATEST_BLOCK_HEIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "veilnet_indexer_latest_block_height",
+        "Height of the last block handled by the indexer"
+    )
+    .unwrap()
+});
+
+pub static BLOCK_LAG_SECONDS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "veilnet_indexer_block_lag_seconds",
+        "Seconds since the last block handled by the indexer was produced, as of the scrape"
+    )
+    .unwrap()
+});
+
+pub static BLOCKS_PROCESSED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "veilnet_indexer_blocks_processed_total",
+        "Blocks handled by the indexer"
+    )
+    .unwrap()
+});
+
+pub static INDEXER_RESTARTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "veilnet_indexer_restarts_total",
+        "Times the indexer was restarted after stalling or failing"
+    )
+    .unwrap()
+});
+
+pub static SKIPPED_ACTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "veilnet_indexer_skipped_actions_total",
+        "Actions on the contract the indexer could not apply",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static QUEUED_JOBS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("veilnet_queue_depth", "Jobs waiting to be processed").unwrap()
+});
+
+pub static IN_FLIGHT_JOBS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "veilnet_jobs_in_flight",
+        "Jobs being fetched, computed or published"
+    )
+    .unwrap()
+});
+
+pub static JOB_STAGE_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "veilnet_job_stage_duration_seconds",
+        "Time a job spent in each stage",
+        &["kind", "stage"],
+        vec![0.1, 1.0, 10.0, 60.0, 300.0, 1800.0, 7200.0, 28800.0]
+    )
+    .unwrap()
+});
+
+pub static IPFS_BYTES_FETCHED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "veilnet_ipfs_fetched_bytes_total",
+        "Bytes downloaded from IPFS"
+    )
+    .unwrap()
+});
+
+pub static IPFS_BYTES_PUBLISHED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "veilnet_ipfs_published_bytes_total",
+        "Bytes uploaded to IPFS"
+    )
+    .unwrap()
+});
+
+pub static FHE_OPERATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "veilnet_fhe_operations_total",
+        "Homomorphic operations evaluated on ciphertexts",
+        &["op"]
+    )
+    .unwrap()
+});
+
+pub static EPOCH_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "veilnet_training_epoch_duration_seconds",
+        "Time to train a model for one epoch",
+        vec![1.0, 10.0, 60.0, 300.0, 1800.0, 7200.0, 28800.0]
+    )
+    .unwrap()
+});
+
+/// Register every metric, the counters of each skip reason start at zero
+pub fn register() {
+    LazyLock::force(&LATEST_BLOCK_HEIGHT);
+    LazyLock::force(&BLOCK_LAG_SECONDS);
+    LazyLock::force(&BLOCKS_PROCESSED);
+    LazyLock::force(&INDEXER_RESTARTS);
+    for reason in SkipReason::ALL {
+        SKIPPED_ACTIONS.with_label_values(&[reason.as_str()]);
+    }
+    LazyLock::force(&QUEUED_JOBS);
+    LazyLock::force(&IN_FLIGHT_JOBS);
+    LazyLock::force(&JOB_STAGE_SECONDS);
+    LazyLock::force(&IPFS_BYTES_FETCHED);
+    LazyLock::force(&IPFS_BYTES_PUBLISHED);
+    LazyLock::force(&FHE_OPERATIONS);
+    LazyLock::force(&EPOCH_SECONDS);
+}
+
+/// Count `n` homomorphic operations of kind `op`
+pub fn count_fhe_ops(op: &str, n: usize) {
+    FHE_OPERATIONS.with_label_values(&[op]).inc_by(n as u64);
+}
+
+/// Every registered metric in the Prometheus text format
+pub fn encode() -> anyhow::Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -0,0 +1,264 @@
+//! Local mirror of the contract state the worker acts on, kept up to date by the indexer.
+//!
+//! The mirror only knows what the backfill and the indexer have seen since the node started,
+//! so an unknown request or proposal means there is no information about it, not that it does
+//! not exist.
+use crate::types::Vote;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use near_lake_primitives::AccountId;
+use tokio::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestStatus {
+    Open,
+    Finished,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestMirror {
+    pub status: RequestStatus,
+    pub workers: Vec<AccountId>, // empty if the request was only seen being closed
+    pub completed_by: HashSet<AccountId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProposalKind {
+    AddWorker(AccountId),
+    RemoveWorker(AccountId),
+    ChangeBaseFee(u128),
+    ChangeStakeAmount(u128),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProposalStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProposalMirror {
+    pub kind: ProposalKind,
+    pub proposer: AccountId,
+    pub status: ProposalStatus,
+    pub votes: HashMap<AccountId, Vote>,
+}
+
+#[derive(Debug, Default)]
+struct MirrorState {
+    requests: HashMap<u32, RequestMirror>,
+    proposals: HashMap<u32, ProposalMirror>,
+}
+
+#[derive(Clone, Default)]
+pub struct ContractMirror {
+    state: Arc<RwLock<MirrorState>>,
+}
+
+impl ContractMirror {
+    /// Record an open request, what is known about it already is kept
+    pub async fn add_request(&self, request_id: u32, workers: Vec<AccountId>) {
+        let mut state = self.state.write().await;
+        let request = state
+            .requests
+            .entry(request_id)
+            .or_insert_with(open_request);
+        request.workers = workers;
+    }
+
+    pub async fn complete_request(&self, request_id: u32, worker: AccountId) {
+        let mut state = self.state.write().await;
+        // a request added before the indexer's start block is only seen being closed
+        let request = state
+            .requests
+            .entry(request_id)
+            .or_insert_with(open_request);
+        // like the contract, the first completion closes the request
+        request.status = RequestStatus::Finished;
+        request.completed_by.insert(worker);
+    }
+
+    pub async fn cancel_request(&self, request_id: u32) {
+        let mut state = self.state.write().await;
+        let request = state
+            .requests
+            .entry(request_id)
+            .or_insert_with(open_request);
+        request.status = RequestStatus::Cancelled;
+    }
+
+    pub async fn add_proposal(&self, proposal_id: u32, kind: ProposalKind, proposer: AccountId) {
+        self.state.write().await.proposals.insert(
+            proposal_id,
+            ProposalMirror {
+                kind,
+                proposer,
+                status: ProposalStatus::Pending,
+                votes: HashMap::new(),
+            },
+        );
+    }
+
+    pub async fn vote(&self, proposal_id: u32, voter: AccountId, vote: Vote) {
+        if let Some(proposal) = self.state.write().await.proposals.get_mut(&proposal_id) {
+            proposal.votes.insert(voter, vote);
+        }
+    }
+
+    /// Record the outcome of a proposal
+    pub async fn execute_proposal(&self, proposal_id: u32, approved: bool) {
+        if let Some(proposal) = self.state.write().await.proposals.get_mut(&proposal_id) {
+            proposal.status = if approved {
+                ProposalStatus::Approved
+            } else {
+                ProposalStatus::Rejected
+            };
+        }
+    }
+
+    pub async fn request(&self, request_id: u32) -> Option<RequestMirror> {
+        self.state.read().await.requests.get(&request_id).cloned()
+    }
+
+    pub async fn is_cancelled(&self, request_id: u32) -> bool {
+        self.request(request_id)
+            .await
+            .is_some_and(|request| request.status == RequestStatus::Cancelled)
+    }
+
+    /// Whether `worker` should still work on a request: it is not cancelled and
+    /// `worker` has not completed it already
+    pub async fn should_start(&self, request_id: u32, worker: &str) -> bool {
+        match self.request(request_id).await {
+            Some(request) => {
+                request.status != RequestStatus::Cancelled
+                    && !request.completed_by.iter().any(|w| w.as_str() == worker)
+            }
+            None => true,
+        }
+    }
+
+    /// Whether the indexer saw `worker` complete the request
+    pub async fn has_completed(&self, request_id: u32, worker: &str) -> bool {
+        self.request(request_id)
+            .await
+            .is_some_and(|request| request.completed_by.iter().any(|w| w.as_str() == worker))
+    }
+
+    pub async fn proposal(&self, proposal_id: u32) -> Option<ProposalMirror> {
+        self.state.read().await.proposals.get(&proposal_id).cloned()
+    }
+}
+
+/// An open request nothing else is known about yet
+fn open_request() -> RequestMirror {
+    RequestMirror {
+        status: RequestStatus::Open,
+        workers: Vec::new(),
+        completed_by: HashSet::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn tracks_requests_from_open_to_finished() {
+        let mirror = ContractMirror::default();
+        let workers = vec![account("a.test.near"), account("b.test.near")];
+        mirror.add_request(1, workers.clone()).await;
+        assert!(mirror.should_start(1, "a.test.near").await);
+        assert!(!mirror.has_completed(1, "a.test.near").await);
+
+        mirror.complete_request(1, account("a.test.near")).await;
+        let request = mirror.request(1).await.unwrap();
+        assert_eq!(request.status, RequestStatus::Finished);
+        assert_eq!(request.workers, workers);
+        assert!(mirror.has_completed(1, "a.test.near").await);
+        assert!(!mirror.should_start(1, "a.test.near").await);
+        // the other worker still contributes its result
+        assert!(mirror.should_start(1, "b.test.near").await);
+        assert!(!mirror.is_cancelled(1).await);
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_are_not_started() {
+        let mirror = ContractMirror::default();
+        mirror.add_request(1, vec![account("a.test.near")]).await;
+        mirror.cancel_request(1).await;
+        assert!(mirror.is_cancelled(1).await);
+        assert!(!mirror.should_start(1, "a.test.near").await);
+    }
+
+    #[tokio::test]
+    async fn records_requests_it_only_sees_being_closed() {
+        let mirror = ContractMirror::default();
+        assert!(mirror.should_start(1, "a.test.near").await);
+        assert!(mirror.request(1).await.is_none());
+
+        mirror.cancel_request(1).await;
+        assert!(mirror.is_cancelled(1).await);
+        assert!(!mirror.should_start(1, "a.test.near").await);
+
+        mirror.complete_request(2, account("a.test.near")).await;
+        let request = mirror.request(2).await.unwrap();
+        assert_eq!(request.status, RequestStatus::Finished);
+        assert!(request.workers.is_empty());
+        assert!(mirror.has_completed(2, "a.test.near").await);
+
+        // adding a request the mirror knows already keeps what it knows
+        mirror.add_request(1, vec![account("a.test.near")]).await;
+        let request = mirror.request(1).await.unwrap();
+        assert_eq!(request.status, RequestStatus::Cancelled);
+        assert_eq!(request.workers, vec![account("a.test.near")]);
+    }
+
+    #[tokio::test]
+    async fn tracks_proposals_and_their_votes() {
+        let mirror = ContractMirror::default();
+        let kind = ProposalKind::ChangeBaseFee(5);
+        mirror
+            .add_proposal(0, kind.clone(), account("a.test.near"))
+            .await;
+        mirror
+            .add_proposal(
+                1,
+                ProposalKind::AddWorker(account("c.test.near")),
+                account("c.test.near"),
+            )
+            .await;
+        mirror.vote(0, account("a.test.near"), Vote::For).await;
+        mirror.vote(0, account("b.test.near"), Vote::Against).await;
+        // votes on proposals the mirror does not know are ignored
+        mirror.vote(7, account("a.test.near"), Vote::For).await;
+        mirror.execute_proposal(7, true).await;
+        assert!(mirror.proposal(7).await.is_none());
+
+        let proposal = mirror.proposal(0).await.unwrap();
+        assert_eq!(proposal.kind, kind);
+        assert_eq!(proposal.proposer, account("a.test.near"));
+        assert_eq!(proposal.status, ProposalStatus::Pending);
+        assert_eq!(proposal.votes.len(), 2);
+        assert_eq!(proposal.votes[&account("b.test.near")], Vote::Against);
+
+        mirror.execute_proposal(0, true).await;
+        mirror.execute_proposal(1, false).await;
+        assert_eq!(
+            mirror.proposal(0).await.unwrap().status,
+            ProposalStatus::Approved
+        );
+        assert_eq!(
+            mirror.proposal(1).await.unwrap().status,
+            ProposalStatus::Rejected
+        );
+    }
+}
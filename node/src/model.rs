@@ -1,93 +1,539 @@
 use std::fs::File;
-use std::io::Read;
-
-use csv::ReaderBuilder;
-use futures::io::BufReader;
-use serde::Deserialize;
-use tfhe::safe_serialization::safe_deserialize_conformant;
-use tfhe::shortint::parameters::PARAM_MESSAGE_2_CARRY_2_KS_PBS;
-use tfhe::{set_server_key, CompressedServerKey, ConfigBuilder, FheUint8};
-/// This function computes the linear regression for the dataset provided
-/// path to the dataset file
-/// and compressed_secret_key as common
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+
+use anyhow::Context;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tfhe::prelude::*;
+use tfhe::safe_serialization::{safe_deserialize_conformant, safe_serialize};
+use tfhe::{set_server_key, CompressedServerKey, Config, FheUint8, ServerKey};
+
+use crate::dataset::{
+    Column, ColumnType, DatasetHeader, DatasetReader, DatasetWriter, FheParameters,
+};
+use crate::metrics::{self, count_fhe_ops};
+
+/// Upper bound for the size of a single serialized key or ciphertext
+const SERIALIZED_SIZE_LIMIT: u64 = 1 << 30;
+
+/// Learning rate expressed as a right shift, i.e. `lr = 2^-LEARNING_RATE_SHIFT`
+const LEARNING_RATE_SHIFT: u8 = 4;
+
+/// Fixed point representation of 0.5 for 8 bit values
+const HALF: u8 = 128;
+
+/// Largest training batch, the averaged gradient of a bigger one could wrap around
+const MAX_BATCH_SIZE: usize = 128;
+
+#[derive(Debug, Clone, clap::Parser)]
+#[group(id = "model-options")]
+pub struct Options {
+    /// Number of rows deserialized and kept in memory at a time while training, at most 128.
+    /// Gradients are averaged over a batch, exactly so for powers of two.
+    #[clap(long, env("TRAINING_BATCH_SIZE"), default_value = "32")]
+    pub batch_size: usize,
+
+    /// Number of threads used for FHE computations, 0 uses every available core.
+    #[clap(long, env("TRAINING_THREADS"), default_value = "0")]
+    pub threads: usize,
+}
+
+/// A single training example: the encrypted features and the encrypted label
+pub type EncryptedSample = (Vec<FheUint8>, FheUint8);
+
+/// The model families a client can request, as the contract stores them
+pub use contract::state::ModelType;
+
+/// Trained model as published to IPFS, enough to evaluate it again for inference
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ModelParams {
+    pub model_type: ModelType,
+    pub parameters: FheParameters, // parameter set of the dataset the model was trained on
+    pub params: Vec<Vec<u8>>,      // serialized encrypted parameters
+}
+
+impl ModelParams {
+    /// The parameters as they are published, a bincode blob
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        bincode::serialize(self).context("failed to encode model parameters")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        bincode::deserialize(bytes).context("failed to decode model parameters")
+    }
+}
 
-/// Structure for CSV rows with encrypted features and label
-#[derive(Deserialize)]
-struct EncryptedRow {
-    features: Vec<Vec<u8>>, // List of encrypted features as Vec<u8>
-    label: Vec<u8>,         // Encrypted label as Vec<u8> (0 or 1)
+/// Create an untrained model of the given family
+pub fn build_model(model_type: ModelType) -> Box<dyn EncryptedModel> {
+    match model_type {
+        ModelType::LinearRegression => Box::new(LinearRegression::default()),
+        ModelType::LogisticRegression => Box::new(LogisticRegression::default()),
+        ModelType::Perceptron => Box::new(Perceptron::default()),
+    }
 }
 
-pub async fn run_linear_regression(data_file: &mut File, compressed_secret_key: Vec<u8>, epochs: usize) {
-    let config = ConfigBuilder::with_custom_parameters(PARAM_MESSAGE_2_CARRY_2_KS_PBS).build();
-    let _server_key_deser: CompressedServerKey =
-        safe_deserialize_conformant(compressed_secret_key.as_slice(), 1 << 30, &config.into())
-            .unwrap();
+/// A model that is trained homomorphically on encrypted samples.
+///
+/// The server key has to be set on the current thread, and on every thread of the rayon
+/// pool the methods run in, before any of these methods are called.
+pub trait EncryptedModel: Send + Sync {
+    /// Initialise the encrypted parameters for samples with `feature_count` features
+    fn init(&mut self, feature_count: usize);
+
+    /// Run a single pass over a mini-batch, updating the encrypted parameters.
+    /// An epoch is one call per batch of the dataset.
+    fn train_batch(&mut self, batch: &[EncryptedSample]);
+
+    /// The encrypted parameters, serialized so they can be published to IPFS
+    fn export_params(&self) -> anyhow::Result<Vec<Vec<u8>>>;
+
+    /// Restore parameters previously produced by `export_params`
+    fn import_params(&mut self, params: &[Vec<u8>], config: &Config) -> anyhow::Result<()>;
 
-    set_server_key(_server_key_deser.decompress());
+    /// Evaluate the model on a single encrypted row
+    fn predict(&self, features: &[FheUint8]) -> FheUint8;
+}
+
+/// Linear regression trained with mini-batch gradient descent
+#[derive(Default)]
+pub struct LinearRegression {
+    weights: Vec<FheUint8>,
+    bias: Option<FheUint8>,
+}
+
+impl EncryptedModel for LinearRegression {
+    fn init(&mut self, feature_count: usize) {
+        self.weights = (0..feature_count).map(|_| encrypt_trivial(0)).collect();
+        self.bias = Some(encrypt_trivial(0));
+    }
+
+    fn train_batch(&mut self, batch: &[EncryptedSample]) {
+        let bias = self.bias.get_or_insert_with(|| encrypt_trivial(0));
+        let errors = batch_errors(&self.weights, bias, batch, |z| z);
+        apply_gradient(&mut self.weights, bias, &errors, batch, LEARNING_RATE_SHIFT);
+    }
 
-    let mut buffer = Vec::new();
-    let res = data_file.read_to_end(&mut buffer).unwrap();
-    let mut rdr = ReaderBuilder::new().from_reader(buffer.as_slice());
-    let mut dataset = vec![];
+    fn export_params(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+        serialize_ciphertexts(self.weights.iter().chain(self.bias.iter()))
+    }
+
+    fn import_params(&mut self, params: &[Vec<u8>], config: &Config) -> anyhow::Result<()> {
+        (self.weights, self.bias) = deserialize_params(params, config)?;
+        Ok(())
+    }
+
+    fn predict(&self, features: &[FheUint8]) -> FheUint8 {
+        linear_combination(&self.weights, self.bias.as_ref(), features)
+    }
+}
+
+/// Logistic regression where the sigmoid is replaced by a polynomial approximation
+#[derive(Default)]
+pub struct LogisticRegression {
+    weights: Vec<FheUint8>,
+    bias: Option<FheUint8>,
+}
+
+impl EncryptedModel for LogisticRegression {
+    fn init(&mut self, feature_count: usize) {
+        self.weights = (0..feature_count).map(|_| encrypt_trivial(0)).collect();
+        self.bias = Some(encrypt_trivial(0));
+    }
+
+    fn train_batch(&mut self, batch: &[EncryptedSample]) {
+        let bias = self.bias.get_or_insert_with(|| encrypt_trivial(0));
+        let errors = batch_errors(&self.weights, bias, batch, |z| sigmoid(&z));
+        apply_gradient(&mut self.weights, bias, &errors, batch, LEARNING_RATE_SHIFT);
+    }
+
+    fn export_params(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+        serialize_ciphertexts(self.weights.iter().chain(self.bias.iter()))
+    }
+
+    fn import_params(&mut self, params: &[Vec<u8>], config: &Config) -> anyhow::Result<()> {
+        (self.weights, self.bias) = deserialize_params(params, config)?;
+        Ok(())
+    }
+
+    fn predict(&self, features: &[FheUint8]) -> FheUint8 {
+        sigmoid(&linear_combination(
+            &self.weights,
+            self.bias.as_ref(),
+            features,
+        ))
+    }
+}
+
+/// Single layer perceptron with a step activation, trained with the perceptron rule
+#[derive(Default)]
+pub struct Perceptron {
+    weights: Vec<FheUint8>,
+    bias: Option<FheUint8>,
+}
+
+impl EncryptedModel for Perceptron {
+    fn init(&mut self, feature_count: usize) {
+        self.weights = (0..feature_count).map(|_| encrypt_trivial(0)).collect();
+        self.bias = Some(encrypt_trivial(0));
+    }
+
+    fn train_batch(&mut self, batch: &[EncryptedSample]) {
+        let bias = self.bias.get_or_insert_with(|| encrypt_trivial(0));
+        // the perceptron rule only moves the weights on a misclassification, with a learning
+        // rate of 1 the weights move by the mean error of the batch
+        let errors = batch_errors(&self.weights, bias, batch, |z| step(&z));
+        apply_gradient(&mut self.weights, bias, &errors, batch, 0);
+    }
+
+    fn export_params(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+        serialize_ciphertexts(self.weights.iter().chain(self.bias.iter()))
+    }
 
-    for result in rdr.deserialize() {
-        let record: EncryptedRow = result.unwrap();
-        let features: Vec<FheUint8> = record
-            .features
+    fn import_params(&mut self, params: &[Vec<u8>], config: &Config) -> anyhow::Result<()> {
+        (self.weights, self.bias) = deserialize_params(params, config)?;
+        Ok(())
+    }
+
+    fn predict(&self, features: &[FheUint8]) -> FheUint8 {
+        step(&linear_combination(
+            &self.weights,
+            self.bias.as_ref(),
+            features,
+        ))
+    }
+}
+
+/// Weighted sum of the features: z = weights * features (dot product)
+fn dot(weights: &[FheUint8], features: &[FheUint8]) -> FheUint8 {
+    count_fhe_ops("mul", weights.len());
+    count_fhe_ops("add", weights.len());
+    weights
+        .par_iter()
+        .zip(features.par_iter())
+        .map(|(w, x)| w * x)
+        .reduce(|| encrypt_trivial(0), |z, wx| z + &wx)
+}
+
+/// z = weights * features + bias, a missing bias counts as zero
+fn linear_combination(
+    weights: &[FheUint8],
+    bias: Option<&FheUint8>,
+    features: &[FheUint8],
+) -> FheUint8 {
+    let z = dot(weights, features);
+    match bias {
+        Some(bias) => {
+            count_fhe_ops("add", 1);
+            z + bias
+        }
+        None => z,
+    }
+}
+
+/// step(z) is 1 when z >= 0.5 and 0 otherwise
+fn step(z: &FheUint8) -> FheUint8 {
+    count_fhe_ops("compare", 1);
+    count_fhe_ops("select", 1);
+    z.ge(HALF)
+        .if_then_else(&encrypt_trivial(1), &encrypt_trivial(0))
+}
+
+/// Polynomial approximation of the sigmoid: sigmoid(z) ≈ 0.5 + z/4 - z^3/48
+///
+/// The cubic term is rounded to z^3/64 so it can be computed with a shift.
+fn sigmoid(z: &FheUint8) -> FheUint8 {
+    count_fhe_ops("mul", 2);
+    count_fhe_ops("shift", 2);
+    count_fhe_ops("add", 1);
+    count_fhe_ops("sub", 1);
+    let cubic = &(z * z) * z;
+    let linear: FheUint8 = z >> 2u8;
+    linear + HALF - &(cubic >> 6u8)
+}
+
+/// Prediction error of every row of the batch against the current parameters,
+/// rows are evaluated in parallel
+fn batch_errors(
+    weights: &[FheUint8],
+    bias: &FheUint8,
+    batch: &[EncryptedSample],
+    activation: impl Fn(FheUint8) -> FheUint8 + Sync,
+) -> Vec<FheUint8> {
+    count_fhe_ops("add", batch.len());
+    count_fhe_ops("sub", batch.len());
+    batch
+        .par_iter()
+        .map(|(features, label)| &activation(dot(weights, features) + bias) - label)
+        .collect()
+}
+
+/// Update weights and bias with the mean gradient of the batch:
+/// w = w - learning_rate * sum(error * feature) / batch_size, every feature is updated in parallel
+///
+/// The division is a shift by log2 of the batch size, rounded down for sizes that are not a
+/// power of two. Every term is divided before summing, so the sum of up to [`MAX_BATCH_SIZE`]
+/// terms can not wrap around.
+fn apply_gradient(
+    weights: &mut [FheUint8],
+    bias: &mut FheUint8,
+    errors: &[FheUint8],
+    batch: &[EncryptedSample],
+    learning_rate_shift: u8,
+) {
+    debug_assert!(batch.len() <= MAX_BATCH_SIZE);
+    let batch_shift = batch.len().max(1).ilog2() as u8;
+    let parameters = weights.len() + 1;
+    count_fhe_ops("mul", weights.len() * batch.len());
+    count_fhe_ops("shift", parameters * batch.len() + parameters);
+    count_fhe_ops("add", parameters * batch.len());
+    count_fhe_ops("sub", parameters);
+    weights.par_iter_mut().enumerate().for_each(|(j, w)| {
+        let gradient = errors
             .iter()
-            .map(|f| safe_deserialize_conformant(&f[..], 1 << 30, &config.into()).unwrap())
-            .collect();
-        let label: FheUint8 =
-            safe_deserialize_conformant(&record.label[..], 1 << 30, &config.into()).unwrap();
-        dataset.push((features, label));
-
-        // Initialize encrypted weights to zero
-        let feature_count = dataset[0].0.len();
-        let mut weights: Vec<FheUint8> = (0..feature_count)
-            .map(|_| FheUint8::encrypt(0u8, &server_key_deser.client_key))
-            .collect();
-
-        // Training loop
-        for _ in 0..epochs {
-            for (features, label) in &dataset {
-                // Calculate weighted sum: z = weights * features (dot product)
-                let mut z : FheUint8;
-                for (w, x) in weights.iter().zip(features.iter()) {
-                    z = &z + &(*w * x);
-                }
-
-                // Sigmoid approximation: sigmoid(z) ≈ 0.5 + 0.197 * z
-                let prediction = &z * 0.197
-                    + FheUint8::encrypt(128u8, &server_key_deser.client_key);
-
-                // Calculate error: error = prediction - label
-                let error = &prediction - label;
-
-                // Update weights: w = w - learning_rate * error * feature
-                for (w, x) in weights.iter_mut().zip(features.iter()) {
-                    let gradient =
-                        &error * x * FheUint8::encrypt(learning_rate, &server_key_deser.client_key);
-                    *w = &*w - &gradient;
-                }
+            .zip(batch.iter())
+            .fold(encrypt_trivial(0), |g, (e, (features, _))| {
+                g + &((e * &features[j]) >> batch_shift)
+            });
+        *w = &*w - &(gradient >> learning_rate_shift);
+    });
+    let gradient = errors
+        .iter()
+        .fold(encrypt_trivial(0), |g, e| g + &(e >> batch_shift));
+    *bias = &*bias - &(gradient >> learning_rate_shift);
+}
+
+fn encrypt_trivial(value: u8) -> FheUint8 {
+    FheUint8::try_encrypt_trivial(value).expect("u8 always fits in a FheUint8")
+}
+
+fn serialize_ciphertexts<'a>(
+    params: impl Iterator<Item = &'a FheUint8>,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    params
+        .map(|p| {
+            let mut buffer = Vec::new();
+            safe_serialize(p, &mut buffer, SERIALIZED_SIZE_LIMIT)
+                .map_err(|e| anyhow::anyhow!("failed to serialize parameter: {e}"))?;
+            Ok(buffer)
+        })
+        .collect()
+}
+
+/// Split parameters produced by `export_params` back into weights and bias
+fn deserialize_params(
+    params: &[Vec<u8>],
+    config: &Config,
+) -> anyhow::Result<(Vec<FheUint8>, Option<FheUint8>)> {
+    let Some((bias, weights)) = params.split_last() else {
+        anyhow::bail!("model has no parameters");
+    };
+    let weights = weights
+        .iter()
+        .map(|w| deserialize_ciphertext(w, config))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok((weights, Some(deserialize_ciphertext(bias, config)?)))
+}
+
+fn deserialize_ciphertext(bytes: &[u8], config: &Config) -> anyhow::Result<FheUint8> {
+    safe_deserialize_conformant(bytes, SERIALIZED_SIZE_LIMIT, &(*config).into())
+        .map_err(|e| anyhow::anyhow!("failed to deserialize ciphertext: {e}"))
+}
+
+/// Decompress the client's server key
+fn load_server_key(compressed_server_key: &[u8], config: &Config) -> anyhow::Result<ServerKey> {
+    let server_key: CompressedServerKey = safe_deserialize_conformant(
+        compressed_server_key,
+        SERIALIZED_SIZE_LIMIT,
+        &(*config).into(),
+    )
+    .map_err(|e| anyhow::anyhow!("failed to deserialize server key: {e}"))?;
+
+    Ok(server_key.decompress())
+}
+
+/// Thread pool for FHE computations, the server key is thread local so it is set
+/// on every thread of the pool as it starts
+fn thread_pool(server_key: ServerKey, threads: usize) -> anyhow::Result<rayon::ThreadPool> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .start_handler(move |_| set_server_key(server_key.clone()))
+        .build()
+        .context("failed to build FHE thread pool")
+}
+
+/// Open a dataset and check that its columns can be computed on by the models
+fn open_dataset(data_file: &mut File) -> anyhow::Result<DatasetReader<BufReader<&mut File>>> {
+    data_file.seek(SeekFrom::Start(0))?;
+    let reader = DatasetReader::new(BufReader::new(data_file))?;
+    if let Some(column) = reader
+        .header()
+        .columns
+        .iter()
+        .find(|c| c.column_type != ColumnType::FheUint8)
+    {
+        anyhow::bail!(
+            "column {} has unsupported type {:?}",
+            column.name,
+            column.column_type
+        );
+    }
+    Ok(reader)
+}
+
+/// Deserialize the features of a row, and its label if the dataset has one
+fn deserialize_row(
+    header: &DatasetHeader,
+    row: Vec<Vec<u8>>,
+    config: &Config,
+) -> anyhow::Result<(Vec<FheUint8>, Option<FheUint8>)> {
+    let (features, label) = header.split_label(row);
+    let features = features
+        .iter()
+        .map(|f| deserialize_ciphertext(f, config))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let label = label
+        .map(|l| deserialize_ciphertext(&l, config))
+        .transpose()?;
+    Ok((features, label))
+}
+
+/// This function trains a model of `model_type` on the dataset provided
+/// path to the dataset file
+/// and compressed server key of the client, returning the encrypted parameters.
+///
+/// The dataset is streamed from disk once per epoch, only `batch_size` rows
+/// of ciphertexts are resident at any time.
+pub fn run_training(
+    data_file: &mut File,
+    compressed_server_key: &[u8],
+    epochs: u32,
+    model_type: ModelType,
+    options: &Options,
+) -> anyhow::Result<ModelParams> {
+    anyhow::ensure!(options.batch_size > 0, "batch size must be at least 1");
+    anyhow::ensure!(
+        options.batch_size <= MAX_BATCH_SIZE,
+        "batch size must be at most {MAX_BATCH_SIZE}"
+    );
+    let header = open_dataset(data_file)?.header().clone();
+    if header.label_column.is_none() {
+        anyhow::bail!("training dataset has no label column");
+    }
+    if header.row_count == 0 {
+        anyhow::bail!("dataset is empty");
+    }
+
+    let config = header.parameters.config();
+    let pool = thread_pool(
+        load_server_key(compressed_server_key, &config)?,
+        options.threads,
+    )?;
+
+    let mut model = model_type.build();
+    pool.install(|| model.init(header.feature_count()));
+
+    // Training loop
+    for epoch in 0..epochs {
+        tracing::debug!(epoch, ?model_type, "training epoch");
+        let epoch_timer = metrics::EPOCH_SECONDS.start_timer();
+        let mut reader = open_dataset(data_file)?;
+        let mut batch: Vec<EncryptedSample> = Vec::with_capacity(options.batch_size);
+        while let Some(row) = reader.read_row()? {
+            let (features, label) = deserialize_row(&header, row, &config)?;
+            let label = label.expect("training dataset has a label column");
+            batch.push((features, label));
+            if batch.len() == options.batch_size {
+                pool.install(|| model.train_batch(&batch));
+                batch.clear();
             }
         }
+        if !batch.is_empty() {
+            pool.install(|| model.train_batch(&batch));
+        }
+        epoch_timer.observe_duration();
+    }
 
-        // Decrypt and write weights to JSON
-        let decrypted_weights: Vec<u8> = weights
-            .iter()
-            .map(|w| w.decrypt(&server_key_deser.client_key))
-            .collect();
+    Ok(ModelParams {
+        model_type,
+        parameters: header.parameters,
+        params: model.export_params()?,
+    })
+}
 
-        let result = json!({
-            "request_id": request_id,
-            "weights": decrypted_weights,
-        });
+/// Evaluate a trained model on the encrypted rows of `data_file`, streaming
+/// one encrypted prediction per row to `output` as a single column dataset.
+/// Returns the number of predictions written.
+pub fn run_inference(
+    data_file: &mut File,
+    compressed_server_key: &[u8],
+    model_params: &ModelParams,
+    output: impl Write,
+    options: &Options,
+) -> anyhow::Result<u64> {
+    anyhow::ensure!(options.batch_size > 0, "batch size must be at least 1");
+    let mut reader = open_dataset(data_file)?;
+    let header = reader.header().clone();
+    if header.parameters != model_params.parameters {
+        anyhow::bail!(
+            "inference input uses {:?} but the model was trained with {:?}",
+            header.parameters,
+            model_params.parameters
+        );
+    }
+    // one weight per feature plus the bias, `dot` would silently ignore any extra features
+    let weight_count = model_params.params.len().saturating_sub(1);
+    if weight_count != header.feature_count() {
+        anyhow::bail!(
+            "inference input has {} features but the model has {weight_count} weights",
+            header.feature_count()
+        );
+    }
 
-        // Save results to a JSON file named after the request_id
-        let result_file = format!("{}_weights.json", request_id);
-        std::fs::write(result_file, result.to_string())?;
+    let config = header.parameters.config();
+    let pool = thread_pool(
+        load_server_key(compressed_server_key, &config)?,
+        options.threads,
+    )?;
 
-        Ok(())
+    let mut model = build_model(model_params.model_type);
+    model.import_params(&model_params.params, &config)?;
+
+    let mut writer = DatasetWriter::new(
+        BufWriter::new(output),
+        DatasetHeader {
+            parameters: header.parameters,
+            columns: vec![Column {
+                name: "prediction".to_string(),
+                column_type: ColumnType::FheUint8,
+            }],
+            row_count: header.row_count,
+            label_column: None,
+        },
+    )?;
+    let mut batch = Vec::with_capacity(options.batch_size);
+    loop {
+        let row = reader.read_row()?;
+        if let Some(row) = row {
+            // a label column, if present, is ignored
+            let (features, _) = deserialize_row(&header, row, &config)?;
+            batch.push(features);
+            if batch.len() < options.batch_size {
+                continue;
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+
+        // rows are independent so the whole batch is evaluated in parallel
+        let predictions: Vec<FheUint8> =
+            pool.install(|| batch.par_iter().map(|f| model.predict(f)).collect());
+        for prediction in serialize_ciphertexts(predictions.iter())? {
+            writer.write_row(&[prediction])?;
+        }
+        batch.clear();
     }
+    writer.finish()?;
+
+    Ok(header.row_count)
 }
@@ -0,0 +1,175 @@
+//! Pins published results before the worker submits their CIDs to the contract.
+//!
+//! Results are pinned in the blob store, so its garbage collection keeps them, and optionally
+//! replicated to a remote pinning service implementing the
+//! [IPFS Pinning Service API](https://ipfs.github.io/pinning-services-api-spec/), so they stay
+//! available while this node is offline. A replicated result counts once the service reports
+//! it `pinned`. Nothing is fetched back: a fetch through this node's own store would be served
+//! from the copy it just published and prove nothing about anyone else reaching it.
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use reqwest::{RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::ipfs_service::IpfsService;
+use crate::storage::{self, Storage};
+
+#[derive(Debug, Clone, clap::Parser)]
+#[group(id = "pinning-options")]
+pub struct Options {
+    /// Endpoint of a remote pinning service results are replicated to, e.g.
+    /// `https://api.pinata.cloud/psa`. Needs the `ipfs` blob store.
+    #[clap(long, env("PINNING_SERVICE_URL"))]
+    pub pinning_service_url: Option<Url>,
+
+    /// Access token of the remote pinning service.
+    #[clap(long, env("PINNING_SERVICE_TOKEN"), requires = "pinning_service_url")]
+    pub pinning_service_token: Option<String>,
+
+    /// How long in seconds to wait for the remote pinning service to pin a result.
+    #[clap(long, env("PIN_TIMEOUT"), default_value = "600")]
+    pub pin_timeout: u64,
+
+    /// The threshold in seconds for connecting to the remote pinning service and for a
+    /// single request to it.
+    #[clap(long, env("PINNING_SERVICE_TIMEOUT"), default_value = "30")]
+    pub pinning_service_timeout: u64,
+
+    /// Delay in milliseconds between checks of a pin that is still queued or pinning.
+    #[clap(long, env("PIN_POLL_INTERVAL_MS"), default_value = "2000")]
+    pub pin_poll_interval_ms: u64,
+}
+
+/// Client for a remote pinning service
+#[derive(Clone)]
+pub struct PinningService {
+    http: reqwest::Client,
+    url: Url,
+    token: Option<String>,
+    timeout: Duration,
+    poll_interval: Duration,
+}
+
+/// The status of a pin request, only the fields the node uses
+#[derive(Debug, Deserialize)]
+struct PinStatus {
+    requestid: String,
+    status: Status,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Queued,
+    Pinning,
+    Pinned,
+    Failed,
+}
+
+impl PinningService {
+    pub fn new(url: &Url, options: &Options) -> anyhow::Result<Self> {
+        let request_timeout = Duration::from_secs(options.pinning_service_timeout);
+        let http = reqwest::Client::builder()
+            .connect_timeout(request_timeout)
+            .timeout(request_timeout)
+            .build()
+            .context("failed to build pinning service HTTP client")?;
+        Ok(Self {
+            http,
+            url: storage::base_url(url),
+            token: options.pinning_service_token.clone(),
+            timeout: Duration::from_secs(options.pin_timeout),
+            poll_interval: Duration::from_millis(options.pin_poll_interval_ms),
+        })
+    }
+
+    /// Ask the service to pin `cid` and wait until it did, for at most the pin timeout
+    pub async fn pin(&self, cid: &str, name: &str) -> anyhow::Result<()> {
+        tokio::time::timeout(self.timeout, self.pin_and_poll(cid, name))
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "pinning service did not pin {cid} within {:?}",
+                    self.timeout
+                )
+            })?
+    }
+
+    async fn pin_and_poll(&self, cid: &str, name: &str) -> anyhow::Result<()> {
+        let request = self
+            .authorize(self.http.post(self.url.join("pins")?))
+            .json(&json!({ "cid": cid, "name": name }));
+        let mut pin = send(request).await?;
+        loop {
+            tracing::debug!(cid, requestid = pin.requestid, status = ?pin.status, "remote pin");
+            match pin.status {
+                Status::Pinned => return Ok(()),
+                Status::Failed => anyhow::bail!("pinning service failed to pin {cid}"),
+                Status::Queued | Status::Pinning => {}
+            }
+            tokio::time::sleep(self.poll_interval).await;
+            let url = self.url.join(&format!("pins/{}", pin.requestid))?;
+            pin = send(self.authorize(self.http.get(url))).await?;
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+async fn send(request: RequestBuilder) -> anyhow::Result<PinStatus> {
+    let response = request
+        .send()
+        .await
+        .context("failed to reach pinning service")?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("pinning service returned {status}: {body}");
+    }
+    response.json().await.context("failed to parse pin status")
+}
+
+/// Pins published results locally and, if configured, at a remote pinning service
+#[derive(Clone)]
+pub struct Replicator {
+    ipfs: IpfsService,
+    remote: Option<PinningService>,
+}
+
+impl Replicator {
+    /// `store` is the blob store `ipfs` serves
+    pub fn new(ipfs: IpfsService, store: &Storage, options: &Options) -> anyhow::Result<Self> {
+        let remote = match &options.pinning_service_url {
+            Some(_) if !matches!(store, Storage::Ipfs(_)) => {
+                anyhow::bail!("a remote pinning service needs the ipfs blob store")
+            }
+            Some(url) => Some(PinningService::new(url, options)?),
+            None => None,
+        };
+        Ok(Self { ipfs, remote })
+    }
+
+    /// Pin the result `cid` in the store and wait until the pinning service, if any, reports
+    /// it pinned. `name` labels it at the pinning service.
+    pub async fn pin(&self, cid: &str, name: &str) -> anyhow::Result<()> {
+        self.ipfs
+            .pin(cid)
+            .await
+            .with_context(|| format!("failed to pin {cid}"))?;
+        if let Some(remote) = &self.remote {
+            remote
+                .pin(cid, name)
+                .await
+                .with_context(|| format!("failed to replicate {cid}"))?;
+            tracing::info!(cid, "replicated to the pinning service");
+        }
+        Ok(())
+    }
+}
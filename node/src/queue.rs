@@ -0,0 +1,581 @@
+use crate::types::{InferenceRequest, TrainingRequest};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+
+/// Lifecycle of a job on this worker. A job moves forward through these states, except that
+/// [`RequestQueue::resume`] puts jobs that were interrupted in progress back to `Queued`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Fetching,
+    Training,
+    Publishing,
+    Submitted,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    const IN_PROGRESS: [JobState; 3] =
+        [JobState::Fetching, JobState::Training, JobState::Publishing];
+    const FINISHED: [JobState; 3] = [JobState::Submitted, JobState::Failed, JobState::Cancelled];
+
+    /// Whether a job in this state was interrupted if the worker is starting up
+    pub fn is_in_progress(&self) -> bool {
+        Self::IN_PROGRESS.contains(self)
+    }
+
+    /// Whether a job in this state is done with, it never runs again
+    pub fn is_finished(&self) -> bool {
+        Self::FINISHED.contains(self)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum JobKind {
+    Training(TrainingRequest),
+    Inference(InferenceRequest),
+}
+
+impl JobKind {
+    pub fn request_id(&self) -> JobId {
+        match self {
+            JobKind::Training(request) => request.request_id,
+            JobKind::Inference(request) => request.request_id,
+        }
+    }
+
+    /// The client's compressed server key, by far the biggest part of a job
+    fn compressed_secret_key_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            JobKind::Training(request) => &mut request.data.compressed_secret_key,
+            JobKind::Inference(request) => &mut request.input.compressed_secret_key,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub kind: JobKind,
+    pub state: JobState,
+    pub result_cid: Option<String>, // cid of the published result, once there is one
+    pub error: Option<String>,      // why the job failed
+}
+
+/// Jobs are keyed by the contract's request id, so re-delivered requests are deduplicated.
+/// Training and inference requests share the contract's id counter.
+pub type JobId = u32;
+
+/// Durable queue of the jobs assigned to this worker, backed by sled trees in the data dir:
+///
+/// - `jobs` holds the jobs without their server keys, by id
+/// - `job_keys` holds the server keys of the jobs that may still run, they are deleted as
+///   soon as a job is finished
+/// - `job_states` indexes the jobs by state and id, so nothing has to decode every job
+/// - `pruned_jobs` remembers the ids of finished jobs that were pruned, so they are not
+///   queued again when they are re-delivered
+#[derive(Clone)]
+pub struct RequestQueue {
+    jobs: sled::Tree,
+    keys: sled::Tree,
+    states: sled::Tree,
+    pruned: sled::Tree,
+}
+
+impl RequestQueue {
+    pub fn open(db: &sled::Db) -> anyhow::Result<Self> {
+        let queue = Self {
+            jobs: db.open_tree("jobs").context("failed to open job tree")?,
+            keys: db
+                .open_tree("job_keys")
+                .context("failed to open job key tree")?,
+            states: db
+                .open_tree("job_states")
+                .context("failed to open job state tree")?,
+            pruned: db
+                .open_tree("pruned_jobs")
+                .context("failed to open pruned job tree")?,
+        };
+        if queue.states.is_empty() && !queue.jobs.is_empty() {
+            queue.reindex()?;
+        }
+        Ok(queue)
+    }
+
+    pub fn add_request(&self, request: TrainingRequest) -> anyhow::Result<bool> {
+        self.enqueue(JobKind::Training(request))
+    }
+
+    pub fn add_inference(&self, inference: InferenceRequest) -> anyhow::Result<bool> {
+        self.enqueue(JobKind::Inference(inference))
+    }
+
+    /// Insert a new job, returns false if the same job was already delivered
+    pub fn enqueue(&self, mut kind: JobKind) -> anyhow::Result<bool> {
+        let id = kind.request_id();
+        let key = std::mem::take(kind.compressed_secret_key_mut());
+        let job = encode(&Job {
+            kind,
+            state: JobState::Queued,
+            result_cid: None,
+            error: None,
+        })?;
+        let inserted = (&self.jobs, &self.keys, &self.states, &self.pruned)
+            .transaction(|(jobs, keys, states, pruned)| {
+                if jobs.get(id.to_be_bytes())?.is_some() || pruned.get(id.to_be_bytes())?.is_some()
+                {
+                    return Ok(false);
+                }
+                jobs.insert(&id.to_be_bytes(), job.as_slice())?;
+                keys.insert(&id.to_be_bytes(), key.as_slice())?;
+                states.insert(&index_key(JobState::Queued, id), &[])?;
+                Ok(true)
+            })
+            .map_err(transaction_error)?;
+        if inserted {
+            self.jobs.flush()?;
+            tracing::info!(id, "queued job");
+        } else {
+            tracing::debug!(id, "job already known, skipping");
+        }
+        Ok(inserted)
+    }
+
+    /// The job with its server key, which is empty once the job is finished
+    pub fn get(&self, id: JobId) -> anyhow::Result<Option<Job>> {
+        let Some(value) = self.jobs.get(id.to_be_bytes())? else {
+            return Ok(None);
+        };
+        let mut job: Job = decode(&value)?;
+        if let Some(key) = self.keys.get(id.to_be_bytes())? {
+            *job.kind.compressed_secret_key_mut() = key.to_vec();
+        }
+        Ok(Some(job))
+    }
+
+    /// The queued job with the lowest request id, if any
+    pub fn next_queued(&self) -> anyhow::Result<Option<(JobId, Job)>> {
+        let Some(id) = self.ids(JobState::Queued).next().transpose()? else {
+            return Ok(None);
+        };
+        let job = self.get(id)?.context("indexed job is missing")?;
+        Ok(Some((id, job)))
+    }
+
+    pub fn set_state(&self, id: JobId, state: JobState) -> anyhow::Result<()> {
+        self.update(id, |job| job.state = state)
+    }
+
+    pub fn set_result(&self, id: JobId, result_cid: &str) -> anyhow::Result<()> {
+        self.update(id, |job| job.result_cid = Some(result_cid.to_string()))
+    }
+
+    pub fn fail(&self, id: JobId, error: &anyhow::Error) -> anyhow::Result<()> {
+        self.update(id, |job| {
+            job.state = JobState::Failed;
+            job.error = Some(format!("{error:#}"));
+        })
+    }
+
+    /// Put jobs that were interrupted by a crash or shutdown back in the queue, a job that
+    /// already published its result keeps it so only the submission is repeated.
+    /// Should be called once on startup before the worker starts draining the queue.
+    pub fn resume(&self) -> anyhow::Result<usize> {
+        let mut resumed = 0;
+        for (id, state) in self.in_progress()? {
+            tracing::info!(id, ?state, "resuming job");
+            self.set_state(id, JobState::Queued)?;
+            resumed += 1;
+        }
+        Ok(resumed)
+    }
+
+    /// Delete all but the `keep` most recent finished jobs, their ids are remembered so a
+    /// re-delivered request is not queued again. Returns how many jobs were deleted.
+    pub fn prune(&self, keep: usize) -> anyhow::Result<usize> {
+        let mut finished = Vec::new();
+        for state in JobState::FINISHED {
+            for id in self.ids(state) {
+                finished.push((id?, state));
+            }
+        }
+        finished.sort_unstable_by_key(|(id, _)| std::cmp::Reverse(*id));
+        let mut pruned = 0;
+        for (id, state) in finished.into_iter().skip(keep) {
+            (&self.jobs, &self.keys, &self.states, &self.pruned)
+                .transaction(|(jobs, keys, states, pruned)| {
+                    jobs.remove(&id.to_be_bytes())?;
+                    keys.remove(&id.to_be_bytes())?;
+                    states.remove(&index_key(state, id))?;
+                    pruned.insert(&id.to_be_bytes(), &[])?;
+                    Ok::<_, ConflictableTransactionError<anyhow::Error>>(())
+                })
+                .map_err(transaction_error)?;
+            pruned += 1;
+        }
+        if pruned > 0 {
+            self.jobs.flush()?;
+            tracing::info!(pruned, "pruned finished jobs");
+        }
+        Ok(pruned)
+    }
+
+    /// The ids of the jobs waiting to be processed, in request order
+    pub fn queued(&self) -> anyhow::Result<Vec<JobId>> {
+        self.ids(JobState::Queued).collect()
+    }
+
+    /// Number of jobs waiting to be processed
+    pub fn len_queued(&self) -> anyhow::Result<usize> {
+        Ok(self.states.scan_prefix([JobState::Queued as u8]).count())
+    }
+
+    /// Jobs that are currently being fetched, computed or published
+    pub fn in_progress(&self) -> anyhow::Result<Vec<(JobId, JobState)>> {
+        let mut jobs = Vec::new();
+        for state in JobState::IN_PROGRESS {
+            for id in self.ids(state) {
+                jobs.push((id?, state));
+            }
+        }
+        jobs.sort_unstable_by_key(|(id, _)| *id);
+        Ok(jobs)
+    }
+
+    /// The errors of the `limit` most recent failed jobs, newest first
+    pub fn recent_failures(&self, limit: usize) -> anyhow::Result<Vec<(JobId, String)>> {
+        let mut failures = Vec::new();
+        for id in self.ids(JobState::Failed).rev().take(limit) {
+            let id = id?;
+            let job = self.get(id)?.context("indexed job is missing")?;
+            failures.push((id, job.error.unwrap_or_default()));
+        }
+        Ok(failures)
+    }
+
+    /// The ids of the jobs in `state`, in request order
+    fn ids(&self, state: JobState) -> impl DoubleEndedIterator<Item = anyhow::Result<JobId>> {
+        self.states
+            .scan_prefix([state as u8])
+            .keys()
+            .map(|key| decode_id(&key?[1..]))
+    }
+
+    fn update(&self, id: JobId, f: impl Fn(&mut Job)) -> anyhow::Result<()> {
+        (&self.jobs, &self.keys, &self.states)
+            .transaction(|(jobs, keys, states)| {
+                let Some(value) = jobs.get(id.to_be_bytes())? else {
+                    return abort(anyhow::anyhow!("unknown job {id}"));
+                };
+                let mut job: Job = match decode(&value) {
+                    Ok(job) => job,
+                    Err(err) => return abort(err),
+                };
+                let previous = job.state;
+                f(&mut job);
+                let value = match encode(&job) {
+                    Ok(value) => value,
+                    Err(err) => return abort(err),
+                };
+                jobs.insert(&id.to_be_bytes(), value)?;
+                if job.state != previous {
+                    states.remove(&index_key(previous, id))?;
+                    states.insert(&index_key(job.state, id), &[])?;
+                }
+                if job.state.is_finished() {
+                    keys.remove(&id.to_be_bytes())?;
+                }
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.jobs.flush()?;
+        Ok(())
+    }
+
+    /// Split the server keys out of the jobs and index them by state, for a data dir written
+    /// before there was an index
+    fn reindex(&self) -> anyhow::Result<()> {
+        let mut reindexed = 0;
+        for entry in self.jobs.iter() {
+            let (key, value) = entry?;
+            let id = decode_id(&key)?;
+            let mut job: Job = decode(&value)?;
+            let secret_key = std::mem::take(job.kind.compressed_secret_key_mut());
+            if !job.state.is_finished() {
+                self.keys.insert(key.clone(), secret_key)?;
+            }
+            self.jobs.insert(key, encode(&job)?)?;
+            self.states.insert(index_key(job.state, id), &[])?;
+            reindexed += 1;
+        }
+        self.jobs.flush()?;
+        tracing::info!(reindexed, "indexed jobs by state");
+        Ok(())
+    }
+}
+
+/// Index keys are the state followed by the big endian id, so the jobs in a state are
+/// visited in request order
+fn index_key(state: JobState, id: JobId) -> [u8; 5] {
+    let mut key = [state as u8; 5];
+    key[1..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// Keys are big endian so that iterating the tree visits jobs in request order
+fn decode_id(key: &[u8]) -> anyhow::Result<JobId> {
+    let bytes = key.try_into().context("malformed job key")?;
+    Ok(JobId::from_be_bytes(bytes))
+}
+
+fn abort<T>(err: anyhow::Error) -> Result<T, ConflictableTransactionError<anyhow::Error>> {
+    Err(ConflictableTransactionError::Abort(err))
+}
+
+fn transaction_error(err: TransactionError<anyhow::Error>) -> anyhow::Error {
+    match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => anyhow::Error::new(err).context("job store failed"),
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> anyhow::Result<Vec<u8>> {
+    bincode::serialize(value).context("failed to encode job")
+}
+
+fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> anyhow::Result<T> {
+    bincode::deserialize(bytes).context("failed to decode job")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::ModelType;
+    use crate::types::ModelData;
+
+    fn queue() -> RequestQueue {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        RequestQueue::open(&db).unwrap()
+    }
+
+    fn request(request_id: u32) -> TrainingRequest {
+        TrainingRequest {
+            request_id,
+            epochs: 1,
+            model_type: ModelType::LinearRegression,
+            creator: "alice.test.near".parse().unwrap(),
+            workers: vec!["worker.test.near".parse().unwrap()],
+            data: ModelData {
+                dataset: format!("dataset-{request_id}"),
+                compressed_secret_key: vec![1, 2, 3],
+            },
+        }
+    }
+
+    fn inference(request_id: u32) -> InferenceRequest {
+        InferenceRequest {
+            request_id,
+            creator: "alice.test.near".parse().unwrap(),
+            workers: vec!["worker.test.near".parse().unwrap()],
+            model: "model".to_string(),
+            input: ModelData {
+                dataset: "input".to_string(),
+                compressed_secret_key: vec![4, 5, 6],
+            },
+        }
+    }
+
+    #[test]
+    fn ignores_duplicate_deliveries() {
+        let queue = queue();
+        assert!(queue.add_request(request(1)).unwrap());
+        queue.set_state(1, JobState::Training).unwrap();
+
+        // re-delivered by the indexer or the backfill, even with other contents
+        assert!(!queue.add_request(request(1)).unwrap());
+        assert!(!queue.add_inference(inference(1)).unwrap());
+        let job = queue.get(1).unwrap().unwrap();
+        assert_eq!(job.kind, JobKind::Training(request(1)));
+        assert_eq!(job.state, JobState::Training);
+        assert_eq!(queue.len_queued().unwrap(), 0);
+    }
+
+    #[test]
+    fn hands_out_queued_jobs_in_request_order() {
+        let queue = queue();
+        queue.add_inference(inference(300)).unwrap();
+        queue.add_request(request(2)).unwrap();
+        queue.add_request(request(10)).unwrap();
+        assert_eq!(queue.len_queued().unwrap(), 3);
+
+        let (id, job) = queue.next_queued().unwrap().unwrap();
+        assert_eq!((id, job.kind), (2, JobKind::Training(request(2))));
+        queue.set_state(2, JobState::Fetching).unwrap();
+        assert_eq!(queue.next_queued().unwrap().unwrap().0, 10);
+        queue.set_state(10, JobState::Submitted).unwrap();
+        assert_eq!(queue.next_queued().unwrap().unwrap().0, 300);
+        queue.set_state(300, JobState::Cancelled).unwrap();
+        assert!(queue.next_queued().unwrap().is_none());
+        assert_eq!(queue.in_progress().unwrap(), vec![(2, JobState::Fetching)]);
+    }
+
+    #[test]
+    fn resumes_interrupted_jobs_with_their_results() {
+        let queue = queue();
+        for id in 1..=6 {
+            queue.add_request(request(id)).unwrap();
+        }
+        queue.set_state(1, JobState::Fetching).unwrap();
+        queue.set_state(2, JobState::Training).unwrap();
+        queue.set_state(3, JobState::Publishing).unwrap();
+        queue.set_result(3, "bafkresult").unwrap();
+        queue.set_state(4, JobState::Submitted).unwrap();
+        queue.set_state(5, JobState::Cancelled).unwrap();
+        queue.fail(6, &anyhow::anyhow!("broken dataset")).unwrap();
+
+        assert_eq!(queue.resume().unwrap(), 3);
+        assert_eq!(queue.len_queued().unwrap(), 3);
+        assert!(queue.in_progress().unwrap().is_empty());
+        let job = queue.get(3).unwrap().unwrap();
+        assert_eq!(job.state, JobState::Queued);
+        assert_eq!(job.result_cid.as_deref(), Some("bafkresult"));
+        assert_eq!(queue.get(4).unwrap().unwrap().state, JobState::Submitted);
+        assert_eq!(queue.get(5).unwrap().unwrap().state, JobState::Cancelled);
+        assert_eq!(queue.get(6).unwrap().unwrap().state, JobState::Failed);
+        assert_eq!(queue.resume().unwrap(), 0);
+    }
+
+    #[test]
+    fn records_failures_newest_first() {
+        let queue = queue();
+        for id in 1..=3 {
+            queue.add_request(request(id)).unwrap();
+        }
+        let err = anyhow::anyhow!("bad magic").context("failed to open dataset");
+        queue.fail(1, &err).unwrap();
+        queue.fail(3, &anyhow::anyhow!("timed out")).unwrap();
+
+        let job = queue.get(1).unwrap().unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(
+            job.error.as_deref(),
+            Some("failed to open dataset: bad magic")
+        );
+        assert_eq!(
+            queue.recent_failures(10).unwrap(),
+            vec![
+                (3, "timed out".to_string()),
+                (1, "failed to open dataset: bad magic".to_string())
+            ]
+        );
+        assert_eq!(queue.recent_failures(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn drops_the_server_key_of_finished_jobs() {
+        let queue = queue();
+        for id in 1..=4 {
+            queue.add_request(request(id)).unwrap();
+        }
+        queue.set_state(1, JobState::Publishing).unwrap();
+        queue.set_state(2, JobState::Submitted).unwrap();
+        queue.set_state(3, JobState::Cancelled).unwrap();
+        queue.fail(4, &anyhow::anyhow!("broken dataset")).unwrap();
+
+        assert_eq!(
+            queue.get(1).unwrap().unwrap().kind,
+            JobKind::Training(request(1))
+        );
+        assert_eq!(queue.keys.len(), 1);
+        for id in 2..=4 {
+            let JobKind::Training(stored) = queue.get(id).unwrap().unwrap().kind else {
+                panic!("job {id} is a training job");
+            };
+            assert!(stored.data.compressed_secret_key.is_empty());
+        }
+    }
+
+    #[test]
+    fn prunes_all_but_the_newest_finished_jobs() {
+        let queue = queue();
+        for id in 1..=5 {
+            queue.add_request(request(id)).unwrap();
+        }
+        queue.set_state(1, JobState::Submitted).unwrap();
+        queue.fail(2, &anyhow::anyhow!("timed out")).unwrap();
+        queue.set_state(3, JobState::Cancelled).unwrap();
+        queue.set_state(4, JobState::Training).unwrap();
+
+        assert_eq!(queue.prune(1).unwrap(), 2);
+        assert!(queue.get(1).unwrap().is_none());
+        assert!(queue.get(2).unwrap().is_none());
+        assert_eq!(queue.get(3).unwrap().unwrap().state, JobState::Cancelled);
+        assert!(queue.recent_failures(10).unwrap().is_empty());
+        assert_eq!(queue.in_progress().unwrap(), vec![(4, JobState::Training)]);
+        assert_eq!(queue.next_queued().unwrap().unwrap().0, 5);
+        assert_eq!(queue.prune(1).unwrap(), 0);
+
+        // the backfill delivers requests that are still open on the contract again
+        assert!(!queue.add_request(request(2)).unwrap());
+        assert!(queue.get(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn indexes_jobs_stored_without_an_index() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let jobs = db.open_tree("jobs").unwrap();
+        for (id, state) in [
+            (1, JobState::Submitted),
+            (2, JobState::Training),
+            (3, JobState::Queued),
+        ] {
+            let job = Job {
+                kind: JobKind::Training(request(id)),
+                state,
+                result_cid: None,
+                error: None,
+            };
+            jobs.insert(id.to_be_bytes(), encode(&job).unwrap())
+                .unwrap();
+        }
+
+        let queue = RequestQueue::open(&db).unwrap();
+        assert_eq!(queue.len_queued().unwrap(), 1);
+        assert_eq!(queue.in_progress().unwrap(), vec![(2, JobState::Training)]);
+        assert_eq!(
+            queue.get(2).unwrap().unwrap().kind,
+            JobKind::Training(request(2))
+        );
+        assert_eq!(queue.keys.len(), 2);
+        let JobKind::Training(stored) = queue.get(1).unwrap().unwrap().kind else {
+            panic!("job 1 is a training job");
+        };
+        assert!(stored.data.compressed_secret_key.is_empty());
+    }
+
+    #[test]
+    fn updating_an_unknown_job_fails() {
+        let queue = queue();
+        assert!(queue.set_state(7, JobState::Training).is_err());
+        assert!(queue.get(7).unwrap().is_none());
+    }
+
+    #[test]
+    fn jobs_survive_reopening_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let queue = RequestQueue::open(&sled::open(dir.path()).unwrap()).unwrap();
+            queue.add_inference(inference(4)).unwrap();
+            queue.set_state(4, JobState::Publishing).unwrap();
+        }
+        let queue = RequestQueue::open(&sled::open(dir.path()).unwrap()).unwrap();
+        assert_eq!(queue.resume().unwrap(), 1);
+        assert_eq!(
+            queue.next_queued().unwrap().unwrap().1.kind,
+            JobKind::Inference(inference(4))
+        );
+    }
+}
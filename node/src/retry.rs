@@ -0,0 +1,134 @@
+//! Retries of the requests the blob stores send, for failures that may go away on their own.
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub(crate) backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt.min(16)))
+    }
+
+    /// Run `call` until it succeeds, fails with an error retrying won't fix, or runs out of
+    /// retries. `request` names the request in logs and errors, e.g. `IPFS pin/add`.
+    pub(crate) async fn run<T, F, Fut>(&self, request: &str, call: F) -> anyhow::Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match call().await {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt < self.max_retries && is_transient(&err) => {
+                    let delay = self.delay(attempt);
+                    tracing::warn!(request, attempt, ?delay, ?err, "request failed, retrying");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    return Err(
+                        err.context(format!("{request} failed after {} attempts", attempt + 1))
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// An API answered with an error status
+#[derive(Debug)]
+pub(crate) struct StatusError {
+    /// The API that answered, e.g. `IPFS API`
+    pub(crate) api: &'static str,
+    pub(crate) status: StatusCode,
+    pub(crate) body: String,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} returned {}: {}", self.api, self.status, self.body)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Network errors, timeouts, rate limiting and server errors may go away on their own
+pub(crate) fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            err.is_connect() || err.is_timeout() || err.is_request() || err.is_body()
+        } else if let Some(err) = cause.downcast_ref::<StatusError>() {
+            err.status.is_server_error() || err.status == StatusCode::TOO_MANY_REQUESTS
+        } else {
+            false
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_error(status: StatusCode) -> anyhow::Error {
+        StatusError {
+            api: "test API",
+            status,
+            body: String::new(),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn classifies_transient_errors() {
+        for status in [
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            assert!(is_transient(&status_error(status)), "{status}");
+        }
+        for status in [
+            StatusCode::BAD_REQUEST,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::NOT_FOUND,
+        ] {
+            assert!(!is_transient(&status_error(status)), "{status}");
+        }
+        // the cause is found below any context
+        let err = status_error(StatusCode::BAD_GATEWAY).context("failed to pin");
+        assert!(is_transient(&err));
+        assert!(!is_transient(&anyhow::anyhow!("invalid CID")));
+
+        // nothing listens on port 1
+        let err = reqwest::Client::new()
+            .post("http://127.0.0.1:1/api/v0/version")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_transient(&err.into()));
+    }
+
+    #[test]
+    fn doubles_the_retry_delay() {
+        let retry = RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(500),
+        };
+        assert_eq!(retry.delay(0), Duration::from_millis(500));
+        assert_eq!(retry.delay(1), Duration::from_secs(1));
+        assert_eq!(retry.delay(2), Duration::from_secs(2));
+        // the exponent stops growing, so the delay does not overflow
+        assert_eq!(retry.delay(u32::MAX), retry.delay(16));
+    }
+}
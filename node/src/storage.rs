@@ -0,0 +1,266 @@
+//! Where datasets, models and results are stored, selected with `--blob-store`.
+//!
+//! Blobs are addressed by the CID of their content, so a blob is verified the same way whichever
+//! store it came from. The IPFS store uses the CIDs the IPFS node assigns, the local and S3
+//! stores a CIDv1 of the whole content as a single raw block.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use reqwest::Url;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::cid::{Cid, CidExt};
+use crate::ipfs::{self, IpfsHandler};
+use crate::s3::{self, S3Store};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobStoreKind {
+    /// An IPFS node or pinning service, see the `--ipfs-*` options
+    Ipfs,
+    /// A local directory, for development and tests
+    Local,
+    /// An S3-compatible object store, see the `--s3-*` options
+    S3,
+}
+
+#[derive(Debug, Clone, clap::Parser)]
+#[group(id = "storage-options")]
+pub struct Options {
+    /// Where blobs are stored and fetched from.
+    #[clap(long, env("BLOB_STORE"), value_enum, default_value = "ipfs")]
+    pub blob_store: BlobStoreKind,
+
+    /// Directory of the `local` blob store.
+    #[clap(long, env("LOCAL_STORE_DIR"), default_value = "./blobs")]
+    pub local_store_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub s3_options: s3::Options,
+}
+
+/// Stores blobs by content id
+pub trait BlobStore: Send + Sync {
+    /// Store a blob, returns its content id
+    fn put_bytes(&self, data: Bytes) -> impl Future<Output = anyhow::Result<String>> + Send;
+
+    /// Store the file at `path`, streamed so it never has to fit in memory
+    fn put_file(&self, path: &Path) -> impl Future<Output = anyhow::Result<String>> + Send;
+
+    /// Fetch the blob `id` to `dest`. Nothing is written to `dest` unless the content matches
+    /// `id`, content that does not fails with a [`CidError`](crate::cid::CidError).
+    fn get(&self, id: &str, dest: &Path) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Keep the blob `id` stored, e.g. through garbage collection
+    fn pin(&self, id: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Let the blob `id` be removed again
+    fn unpin(&self, id: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Whether the store answers requests
+    fn is_reachable(&self) -> impl Future<Output = bool> + Send;
+
+    /// Store JSON data, e.g. model parameters
+    fn put_json(&self, data: &Value) -> impl Future<Output = anyhow::Result<String>> + Send {
+        async move {
+            let json = serde_json::to_vec(data).context("failed to serialize JSON")?;
+            self.put_bytes(json.into()).await
+        }
+    }
+}
+
+/// The blob store selected in the [`Options`]
+#[derive(Clone)]
+pub enum Storage {
+    Ipfs(IpfsHandler),
+    Local(LocalStore),
+    S3(S3Store),
+}
+
+macro_rules! dispatch {
+    ($storage:expr, $store:ident => $call:expr) => {
+        match $storage {
+            Storage::Ipfs($store) => $call,
+            Storage::Local($store) => $call,
+            Storage::S3($store) => $call,
+        }
+    };
+}
+
+impl Storage {
+    pub fn new(options: &Options, ipfs_options: &ipfs::Options) -> anyhow::Result<Self> {
+        Ok(match options.blob_store {
+            BlobStoreKind::Ipfs => Storage::Ipfs(IpfsHandler::new(ipfs_options)?),
+            BlobStoreKind::Local => Storage::Local(LocalStore::new(&options.local_store_dir)?),
+            BlobStoreKind::S3 => Storage::S3(S3Store::new(&options.s3_options)?),
+        })
+    }
+}
+
+impl BlobStore for Storage {
+    async fn put_bytes(&self, data: Bytes) -> anyhow::Result<String> {
+        dispatch!(self, store => store.put_bytes(data).await)
+    }
+
+    async fn put_file(&self, path: &Path) -> anyhow::Result<String> {
+        dispatch!(self, store => store.put_file(path).await)
+    }
+
+    async fn get(&self, id: &str, dest: &Path) -> anyhow::Result<()> {
+        dispatch!(self, store => store.get(id, dest).await)
+    }
+
+    async fn pin(&self, id: &str) -> anyhow::Result<()> {
+        dispatch!(self, store => store.pin(id).await)
+    }
+
+    async fn unpin(&self, id: &str) -> anyhow::Result<()> {
+        dispatch!(self, store => store.unpin(id).await)
+    }
+
+    async fn is_reachable(&self) -> bool {
+        dispatch!(self, store => store.is_reachable().await)
+    }
+}
+
+/// Blobs in a local directory, named by their CID
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir).context("failed to create the local store directory")?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn path(&self, cid: &Cid) -> PathBuf {
+        self.dir.join(cid.to_string())
+    }
+
+    /// Copy `chunks` into the store, named by their CID once all of them are written
+    async fn put_stream<S, E>(&self, chunks: S) -> anyhow::Result<String>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: Into<anyhow::Error>,
+    {
+        let (partial, digest) = write_partial(chunks, &self.dir).await?;
+        let cid = Cid::from_sha256(&digest);
+        persist(partial, &self.path(&cid))?;
+        Ok(cid.to_string())
+    }
+}
+
+impl BlobStore for LocalStore {
+    async fn put_bytes(&self, data: Bytes) -> anyhow::Result<String> {
+        self.put_stream(futures::stream::iter([anyhow::Ok(data)]))
+            .await
+    }
+
+    async fn put_file(&self, path: &Path) -> anyhow::Result<String> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .context("failed to open file")?;
+        self.put_stream(ReaderStream::new(file)).await
+    }
+
+    async fn get(&self, id: &str, dest: &Path) -> anyhow::Result<()> {
+        let cid = Cid::parse(id)?;
+        let file = tokio::fs::File::open(self.path(&cid))
+            .await
+            .with_context(|| format!("blob {id} is not in the local store"))?;
+        write_verified(ReaderStream::new(file), &cid, dest).await
+    }
+
+    /// Blobs stay in the local store until they are removed by hand
+    async fn pin(&self, _id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn unpin(&self, _id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn is_reachable(&self) -> bool {
+        tokio::fs::metadata(&self.dir)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+    }
+}
+
+/// Write `chunks` to `dest` once their sha2-256 matches `cid`, a CID of a single raw block
+pub(crate) async fn write_verified<S, E>(chunks: S, cid: &Cid, dest: &Path) -> anyhow::Result<()>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<anyhow::Error>,
+{
+    let (partial, digest) = write_partial(chunks, parent_dir(dest)).await?;
+    cid.verify_sha256(&digest)?;
+    persist(partial, dest)
+}
+
+/// Write `chunks` to a temporary file in `dir`, returns it with the sha2-256 of its content
+async fn write_partial<S, E>(mut chunks: S, dir: &Path) -> anyhow::Result<(NamedTempFile, Vec<u8>)>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<anyhow::Error>,
+{
+    let (partial, mut file) = partial_file(dir)?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(Into::into)?;
+        hasher.update(&chunk);
+        file.write_all(&chunk)
+            .await
+            .context("failed to write chunk to file")?;
+    }
+    file.flush().await.context("failed to write file")?;
+    Ok((partial, hasher.finalize().to_vec()))
+}
+
+/// A temporary file in `dir`, removed when dropped unless it is persisted, so an error never
+/// leaves a partial file behind
+pub(crate) fn partial_file(dir: &Path) -> anyhow::Result<(NamedTempFile, tokio::fs::File)> {
+    std::fs::create_dir_all(dir).context("failed to create directory")?;
+    let partial = tempfile::Builder::new()
+        .prefix(".")
+        .suffix(".partial")
+        .tempfile_in(dir)
+        .context("failed to create file")?;
+    let file = tokio::fs::File::from_std(partial.reopen().context("failed to open file")?);
+    Ok((partial, file))
+}
+
+/// Atomically move a complete file into place
+pub(crate) fn persist(partial: NamedTempFile, dest: &Path) -> anyhow::Result<()> {
+    partial
+        .persist(dest)
+        .context("failed to move the verified file into place")?;
+    Ok(())
+}
+
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// The base URL of an API, ending with a slash: `Url::join` replaces the last path segment
+/// unless the base ends with one
+pub(crate) fn base_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
+use bytes::Bytes;
 use near_lake_primitives::AccountId;
+use crate::model::ModelType;
 #[derive(Clone, Debug)]
 pub struct LatestBlockHeight {
     pub account_id: AccountId,
@@ -15,21 +16,31 @@ impl LatestBlockHeight {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct ModelData {
     pub dataset: String,                // cid for ipfs
     pub compressed_secret_key: Vec<u8>, // the compressed serialized secret for the client
 }
 
-#[derive(Default)]
-pub struct RequestQueue {
-    pub requests: HashSet<ModelData>,
+/// A training request assigned to this worker, with the id the contract gave it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct TrainingRequest {
+    pub request_id: u32,
+    pub epochs: u32,
+    pub model_type: ModelType,
+    pub creator: AccountId,
+    pub workers: Vec<AccountId>,
+    pub data: ModelData,
 }
 
-impl RequestQueue {
-    pub fn add_request(&mut self, request: ModelData) {
-        self.requests.insert(request);
-    }
+/// An inference request assigned to this worker, with the id the contract gave it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct InferenceRequest {
+    pub request_id: u32,
+    pub creator: AccountId,
+    pub workers: Vec<AccountId>,
+    pub model: String,    // cid of the trained model parameters
+    pub input: ModelData, // the encrypted rows to run the model on
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -38,12 +49,90 @@ pub struct RequestArguments {
     pub dataset_cid: String,
     pub compressed_sk: Vec<u8>,
     pub workers: Vec<String>,
+    pub model_type: ModelType,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct InferenceArguments {
+    pub model_cid: String,
+    pub input_cid: String,
+    pub compressed_sk: Vec<u8>,
+    pub workers: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RequestIdArguments {
+    pub request_id: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Vote {
+    For,
+    Against,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct VoteArguments {
+    pub proposal_id: u32,
+    pub vote: Vote,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExecuteProposalArguments {
+    pub proposal_id: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RemoveWorkerArguments {
+    pub worker: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct BaseFeeArguments {
+    pub fee: u128,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct StakeAmountArguments {
+    pub stake: u128,
+}
+
+/// NEP-297 event as logged by the contract with an `EVENT_JSON:` prefix
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ContractEvent {
+    pub standard: String,
+    pub version: String,
+    pub event: String,
+    #[serde(default)]
+    pub data: Option<Value>,
+}
+
+/// Data of the `request_added` and `inference_request_added` events
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RequestAddedData {
+    pub request_id: u32,
+}
+
+/// Data of the `proposal_created` event
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProposalCreatedData {
+    pub proposal_id: u32,
+}
+
+/// Data of the `proposal_executed` event
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProposalExecutedData {
+    pub proposal_id: u32,
+    pub approved: bool,
 }
 
 #[derive(Debug,Clone,PartialEq, Eq)]
 pub enum IpfsMessage {
    FetchFile{cid: String, filename: String},
    PublishJson {data: Value},
+   PublishBytes {data: Bytes},
+   PublishFile {path: String},
+   Pin {cid: String},
    ShutDown
 }
 
@@ -51,5 +140,8 @@ pub enum IpfsMessage {
 #[derive(Debug,Clone,PartialEq, Eq)]
 pub enum IpfsResponse {
     FileFetched {path: String},
-    JsonPublished {cid: String}
+    JsonPublished {cid: String},
+    BytesPublished {cid: String},
+    FilePublished {cid: String},
+    Pinned
 }
@@ -0,0 +1,373 @@
+//! HTTP endpoints for probes and operators: `/healthz`, `/readyz`, `/status` and `/metrics`.
+use crate::indexer::Indexer;
+use crate::metrics;
+use crate::queue::{JobId, JobState, RequestQueue};
+use crate::storage::{BlobStore, Storage};
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::watch;
+
+/// How many failed jobs `/status` reports
+const RECENT_FAILURES: usize = 10;
+/// Upper bound for a single readiness check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, clap::Parser)]
+#[group(id = "web-options")]
+pub struct Options {
+    /// The address the health and status endpoints listen on.
+    #[clap(long, env("VEILNET_WEB_ADDR"), default_value = "0.0.0.0:8080")]
+    pub web_addr: SocketAddr,
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pub indexer: Indexer,
+    pub queue: RequestQueue,
+    pub store: Storage,
+    pub rpc_url: String,
+    pub http: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct ReadyChecks {
+    indexer: bool,
+    store: bool,
+    rpc: bool,
+}
+
+#[derive(Serialize)]
+struct InFlightJob {
+    id: JobId,
+    state: JobState,
+}
+
+#[derive(Serialize)]
+struct JobError {
+    id: JobId,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Status {
+    latest_block_height: u64,
+    indexer_running: bool,
+    indexer_behind: bool,
+    skipped_actions: BTreeMap<&'static str, u64>,
+    queued_jobs: usize,
+    in_flight_jobs: Vec<InFlightJob>,
+    last_errors: Vec<JobError>,
+}
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+/// Serve the endpoints until `shutdown` is signalled
+pub async fn serve(
+    options: &Options,
+    state: AppState,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(options.web_addr).await?;
+    tracing::info!(addr = %options.web_addr, "serving health and status endpoints");
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        })
+        .await?;
+    Ok(())
+}
+
+/// The process is up and serving requests
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// The node can make progress: the indexer keeps up and the blob store and the RPC answer
+async fn readyz(State(state): State<AppState>) -> Response {
+    let (store, rpc) = tokio::join!(
+        tokio::time::timeout(CHECK_TIMEOUT, state.store.is_reachable()),
+        tokio::time::timeout(CHECK_TIMEOUT, rpc_is_reachable(&state)),
+    );
+    let checks = ReadyChecks {
+        indexer: !state.indexer.is_behind().await,
+        store: store.unwrap_or(false),
+        rpc: rpc.unwrap_or(false),
+    };
+    let code = if checks.indexer && checks.store && checks.rpc {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(checks)).into_response()
+}
+
+async fn status(State(state): State<AppState>) -> Response {
+    match collect_status(&state).await {
+        Ok(status) => Json(status).into_response(),
+        Err(err) => {
+            tracing::warn!(?err, "failed to collect status");
+            let body = Json(json!({ "error": format!("{err:#}") }));
+            (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        }
+    }
+}
+
+/// Prometheus scrape endpoint, the block lag and queue gauges are refreshed on every scrape
+async fn metrics(State(state): State<AppState>) -> Response {
+    let encoded = refresh_metrics(&state)
+        .await
+        .and_then(|()| metrics::encode());
+    match encoded {
+        Ok(body) => body.into_response(),
+        Err(err) => {
+            tracing::warn!(?err, "failed to encode metrics");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response()
+        }
+    }
+}
+
+async fn refresh_metrics(state: &AppState) -> anyhow::Result<()> {
+    // the lag grows while no block arrives, so it can't be set when a block is handled
+    if let Some(lag) = state.indexer.latest_block_lag().await {
+        metrics::BLOCK_LAG_SECONDS.set(lag.as_secs() as i64);
+    }
+    metrics::QUEUED_JOBS.set(state.queue.len_queued()? as i64);
+    metrics::IN_FLIGHT_JOBS.set(state.queue.in_progress()?.len() as i64);
+    Ok(())
+}
+
+async fn collect_status(state: &AppState) -> anyhow::Result<Status> {
+    let skipped_actions = state
+        .indexer
+        .skipped_actions()
+        .await
+        .into_iter()
+        .map(|(reason, count)| (reason.as_str(), count))
+        .collect();
+    Ok(Status {
+        latest_block_height: state.indexer.latest_block_height().await,
+        indexer_running: state.indexer.is_running().await,
+        indexer_behind: state.indexer.is_behind().await,
+        skipped_actions,
+        queued_jobs: state.queue.len_queued()?,
+        in_flight_jobs: state
+            .queue
+            .in_progress()?
+            .into_iter()
+            .map(|(id, state)| InFlightJob { id, state })
+            .collect(),
+        last_errors: state
+            .queue
+            .recent_failures(RECENT_FAILURES)?
+            .into_iter()
+            .map(|(id, error)| JobError { id, error })
+            .collect(),
+    })
+}
+
+async fn rpc_is_reachable(state: &AppState) -> bool {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": "veilnet",
+        "method": "status",
+        "params": [],
+    });
+    let response = state.http.post(&state.rpc_url).json(&request).send().await;
+    matches!(response, Ok(response) if response.status().is_success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, SystemClock};
+    use crate::indexer;
+    use crate::model::ModelType;
+    use crate::storage::LocalStore;
+    use crate::types::{LatestBlockHeight, ModelData, TrainingRequest};
+
+    use std::sync::Arc;
+
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use axum::routing::post;
+    use clap::Parser;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    /// State with a fresh indexer, a local store in `dir` and the RPC at `rpc_url`
+    fn state(dir: &std::path::Path, rpc_url: &str) -> AppState {
+        let options = indexer::Options::try_parse_from(["indexer"]).unwrap();
+        let latest_block_height = LatestBlockHeight {
+            account_id: "worker.test.near".parse().unwrap(),
+            block_height: 0,
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        AppState {
+            indexer: Indexer::new(latest_block_height, &options, Arc::new(SystemClock)),
+            queue: RequestQueue::open(&db).unwrap(),
+            store: Storage::Local(LocalStore::new(dir).unwrap()),
+            rpc_url: rpc_url.to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// An RPC node that answers every request
+    async fn rpc_node() -> String {
+        let router = Router::new().route("/", post(|| async { Json(json!({ "result": {} })) }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{addr}/")
+    }
+
+    /// Let the indexer handle block `height`, produced just now
+    async fn handle_block(state: &AppState, height: u64) {
+        let now = SystemClock.unix_time().as_nanos() as u64;
+        state
+            .indexer
+            .update_block_height_and_timestamp(height, now)
+            .await;
+    }
+
+    async fn get(state: AppState, uri: &str) -> (StatusCode, Vec<u8>) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = router(state).oneshot(request).await.unwrap();
+        let code = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (code, body.to_vec())
+    }
+
+    async fn get_json(state: AppState, uri: &str) -> (StatusCode, Value) {
+        let (code, body) = get(state, uri).await;
+        (code, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn request(request_id: u32) -> TrainingRequest {
+        TrainingRequest {
+            request_id,
+            epochs: 1,
+            model_type: ModelType::LinearRegression,
+            creator: "alice.test.near".parse().unwrap(),
+            workers: vec!["worker.test.near".parse().unwrap()],
+            data: ModelData {
+                dataset: "dataset".to_string(),
+                compressed_secret_key: vec![],
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn healthz_answers_while_the_node_is_not_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let (code, body) = get(state(dir.path(), "http://127.0.0.1:1/"), "/healthz").await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body, b"ok");
+    }
+
+    #[tokio::test]
+    async fn readyz_is_ready_once_every_check_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path(), &rpc_node().await);
+        handle_block(&state, 100).await;
+
+        let (code, checks) = get_json(state, "/readyz").await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(
+            checks,
+            json!({ "indexer": true, "store": true, "rpc": true })
+        );
+    }
+
+    #[tokio::test]
+    async fn readyz_reports_the_failing_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        // no block handled yet and nothing listening for RPC requests
+        let state = state(dir.path(), "http://127.0.0.1:1/");
+
+        let (code, checks) = get_json(state, "/readyz").await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            checks,
+            json!({ "indexer": false, "store": true, "rpc": false })
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_exports_every_metric() {
+        metrics::register();
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path(), "http://127.0.0.1:1/");
+        handle_block(&state, 7).await;
+
+        let (code, body) = get(state, "/metrics").await;
+        assert_eq!(code, StatusCode::OK);
+        let body = String::from_utf8(body).unwrap();
+        for name in [
+            "veilnet_indexer_latest_block_height",
+            "veilnet_indexer_block_lag_seconds",
+            "veilnet_indexer_blocks_processed_total",
+            "veilnet_indexer_restarts_total",
+            "veilnet_queue_depth",
+            "veilnet_jobs_in_flight",
+            "veilnet_ipfs_fetched_bytes_total",
+            "veilnet_ipfs_published_bytes_total",
+        ] {
+            assert!(body.contains(&format!("\n{name} ")), "{name} is missing");
+        }
+        for reason in indexer::SkipReason::ALL {
+            let series = format!(
+                "veilnet_indexer_skipped_actions_total{{reason=\"{}\"}}",
+                reason.as_str()
+            );
+            assert!(body.contains(&series), "{series} is missing");
+        }
+    }
+
+    #[tokio::test]
+    async fn status_reports_the_indexer_and_the_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path(), "http://127.0.0.1:1/");
+        handle_block(&state, 42).await;
+        for id in 1..=4 {
+            state.queue.add_request(request(id)).unwrap();
+        }
+        state.queue.set_state(2, JobState::Training).unwrap();
+        state.queue.set_state(3, JobState::Submitted).unwrap();
+        state
+            .queue
+            .fail(4, &anyhow::anyhow!("dataset not found"))
+            .unwrap();
+
+        let (code, status) = get_json(state, "/status").await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(
+            status,
+            json!({
+                "latest_block_height": 42,
+                "indexer_running": true,
+                "indexer_behind": false,
+                "skipped_actions": {},
+                "queued_jobs": 1,
+                "in_flight_jobs": [{ "id": 2, "state": "Training" }],
+                "last_errors": [{ "id": 4, "error": "dataset not found" }],
+            })
+        );
+    }
+}
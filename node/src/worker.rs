@@ -1,50 +1,461 @@
-use crate::config::{Config, ContractConfig};
-use crate::protocol::ProtocolState;
+use crate::cache::DatasetCache;
+use crate::config::Config;
+use crate::ipfs_service::IpfsService;
+use crate::metrics;
+use crate::mirror::ContractMirror;
+use crate::model::{self, ModelParams};
+use crate::pinning::Replicator;
+use crate::queue::{Job, JobId, JobKind, JobState, RequestQueue};
+use crate::types::{InferenceRequest, TrainingRequest};
 
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+
+use anyhow::Context;
+use contract::state::{InferenceState, RequestsState};
 use near_account_id::AccountId;
 use near_crypto::InMemorySigner;
+use tokio::sync::watch;
 
 use serde_json::json;
 
-pub async fn fetch_contract_state(
+/// How long the worker waits before checking an empty queue again
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a job is attempted before it is failed, only failures fetching, publishing or
+/// submitting are retried
+const MAX_JOB_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of a job, doubled for every further retry
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// How many finished jobs are kept as a record of what the worker did, older ones are pruned
+const FINISHED_JOB_RETENTION: usize = 1000;
+
+/// A job stopped between stages because the node is shutting down, it stays in progress
+/// and is resumed on the next start
+#[derive(Debug)]
+struct ShuttingDown;
+
+impl std::fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the worker is shutting down")
+    }
+}
+
+impl std::error::Error for ShuttingDown {}
+
+pub async fn complete_request(
     rpc_client: &near_fetch::Client,
+    signer: &InMemorySigner,
     contract_id: &AccountId,
-) -> anyhow::Result<contract::Contract> {
-    let contract_state: contract::Contract = rpc_client
-        .view(contract_id, "state")
+    request_id: u32,
+    model_cid: &str,
+) -> anyhow::Result<()> {
+    tracing::info!(request_id, model_cid, %signer.account_id, "completing request");
+    rpc_client
+        .call(signer, contract_id, "complete_request")
+        .args_json(json!({
+            "request_id": request_id,
+            "model_cid": model_cid,
+        }))
+        .max_gas()
+        .retry_exponential(10, 5)
+        .transact()
         .await
         .map_err(|e| {
-            tracing::warn!(%e, "failed to fetch protocol state");
+            tracing::warn!(%e, "failed to complete request");
             e
-        })?
-        .json()?;
+        })?;
 
-    Ok(contract_state)
+    Ok(())
 }
 
-
-pub async fn vote(
+pub async fn complete_inference_request(
     rpc_client: &near_fetch::Client,
     signer: &InMemorySigner,
     contract_id: &AccountId,
-    public_key: &near_crypto::PublicKey,
-    vote: &contract::state::Vote
-) -> anyhow::Result<bool> {
-    tracing::info!(%public_key, %signer.account_id, "voting for proposal");
-    let result = rpc_client
-        .call(signer, contract_id, "vote")
+    request_id: u32,
+    predictions_cid: &str,
+) -> anyhow::Result<()> {
+    tracing::info!(request_id, predictions_cid, %signer.account_id, "completing inference request");
+    rpc_client
+        .call(signer, contract_id, "complete_inference_request")
         .args_json(json!({
-            "public_key": public_key
+            "request_id": request_id,
+            "predictions_cid": predictions_cid,
         }))
         .max_gas()
         .retry_exponential(10, 5)
         .transact()
         .await
         .map_err(|e| {
-            tracing::warn!(%e, "failed to vote for public key");
+            tracing::warn!(%e, "failed to complete inference request");
             e
-        })?
-        .json()?;
+        })?;
+
+    Ok(())
+}
+
+/// Whether the contract already holds `result_cid` for the request, i.e. a submission landed
+/// but the worker stopped before it could record that
+pub async fn has_submitted(
+    rpc_client: &near_fetch::Client,
+    contract_id: &AccountId,
+    request_id: u32,
+    is_training: bool,
+    result_cid: &str,
+) -> anyhow::Result<bool> {
+    let args = json!({ "request_id": request_id });
+    let submitted = if is_training {
+        let request: Option<RequestsState> = rpc_client
+            .view(contract_id, "get_request")
+            .args_json(args)
+            .await
+            .context("failed to fetch request")?
+            .json()?;
+        request.is_some_and(|request| request.model_cid.iter().any(|cid| cid == result_cid))
+    } else {
+        let inference: Option<InferenceState> = rpc_client
+            .view(contract_id, "get_inference_request")
+            .args_json(args)
+            .await
+            .context("failed to fetch inference request")?
+            .json()?;
+        inference.is_some_and(|inference| {
+            inference
+                .predictions_cid
+                .iter()
+                .any(|cid| cid == result_cid)
+        })
+    };
+    Ok(submitted)
+}
+
+/// Drains the request queue filled by the indexer: fetches the encrypted inputs,
+/// runs the FHE computation and publishes the results.
+pub struct Worker {
+    config: Config,
+    rpc_client: near_fetch::Client,
+    ipfs: IpfsService,
+    replicator: Replicator,
+    cache: DatasetCache,
+    queue: RequestQueue,
+    mirror: ContractMirror,
+    model_options: model::Options,
+}
+
+/// What a [`Worker`] processes jobs with, every transfer goes through the IPFS service
+pub struct Dependencies {
+    pub config: Config,
+    pub ipfs: IpfsService,
+    pub replicator: Replicator,
+    pub cache: DatasetCache,
+    pub queue: RequestQueue,
+    pub mirror: ContractMirror,
+    pub model_options: model::Options,
+}
+
+impl Worker {
+    pub fn new(dependencies: Dependencies) -> Self {
+        let Dependencies {
+            config,
+            ipfs,
+            replicator,
+            cache,
+            queue,
+            mirror,
+            model_options,
+        } = dependencies;
+        let rpc_client = near_fetch::Client::new(&config.rpc_url);
+        Self {
+            config,
+            rpc_client,
+            ipfs,
+            replicator,
+            cache,
+            queue,
+            mirror,
+            model_options,
+        }
+    }
+
+    /// Process jobs until `shutdown` is signalled. A job that is in flight when the signal
+    /// arrives stops after its current stage and is resumed on the next start.
+    pub async fn run(self, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        tracing::info!(account_id = %self.config.account_id, "starting worker");
+        let resumed = self.queue.resume()?;
+        if resumed > 0 {
+            tracing::info!(resumed, "resumed interrupted jobs");
+        }
+        self.queue.prune(FINISHED_JOB_RETENTION)?;
+
+        while !*shutdown.borrow() {
+            let Some((id, job)) = self.queue.next_queued()? else {
+                tokio::select! {
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    _ = shutdown.changed() => {}
+                }
+                continue;
+            };
+
+            let worker = self.config.account_id.as_str();
+            if self.mirror.has_completed(id, worker).await {
+                // the submission landed before the worker could record it
+                tracing::info!(id, "request already completed by this worker");
+                self.queue.set_state(id, JobState::Submitted)?;
+                continue;
+            }
+            if !self.mirror.should_start(id, worker).await {
+                tracing::info!(id, "request was cancelled, skipping job");
+                self.queue.set_state(id, JobState::Cancelled)?;
+                continue;
+            }
+
+            match self.process_with_retries(id, job, &mut shutdown).await {
+                Ok(()) => {}
+                Err(err) if err.is::<ShuttingDown>() => {
+                    tracing::info!(id, "interrupted job, it resumes on the next start");
+                }
+                Err(err) if self.mirror.is_cancelled(id).await => {
+                    tracing::info!(id, ?err, "aborted job of a cancelled request");
+                    self.queue.set_state(id, JobState::Cancelled)?;
+                }
+                Err(err) => {
+                    tracing::error!(id, ?err, "job failed");
+                    self.queue.fail(id, &err)?;
+                }
+            }
+            self.queue.prune(FINISHED_JOB_RETENTION)?;
+        }
+        tracing::info!("worker stopped");
+        Ok(())
+    }
+
+    /// Process a job, retrying it with a backoff while it fails outside of the computation
+    async fn process_with_retries(
+        &self,
+        id: JobId,
+        mut job: Job,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        let mut attempt = 1;
+        loop {
+            let Err(err) = self.process(id, job, shutdown).await else {
+                return Ok(());
+            };
+            if attempt == MAX_JOB_ATTEMPTS
+                || err.is::<ShuttingDown>()
+                || !self.is_retryable(id)?
+                || self.mirror.is_cancelled(id).await
+            {
+                return Err(err);
+            }
+
+            let delay = RETRY_DELAY * 2u32.pow(attempt - 1);
+            tracing::warn!(id, attempt, ?delay, ?err, "job failed, retrying");
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.changed() => return Err(ShuttingDown.into()),
+            }
+            attempt += 1;
+            // a retry picks up the result the failed attempt already published
+            job = self
+                .queue
+                .get(id)?
+                .with_context(|| format!("job {id} disappeared from the queue"))?;
+        }
+    }
+
+    /// Whether the job failed talking to IPFS or the RPC rather than in the computation,
+    /// which would fail the same way again
+    fn is_retryable(&self, id: JobId) -> anyhow::Result<bool> {
+        Ok(self
+            .queue
+            .get(id)?
+            .is_some_and(|job| job.state != JobState::Training))
+    }
+
+    /// Run a job and submit its result to the contract. A job that published its result
+    /// before it was interrupted, or before a failed submission, only submits it.
+    async fn process(
+        &self,
+        id: JobId,
+        job: Job,
+        shutdown: &watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        let is_training = matches!(job.kind, JobKind::Training(_));
+        let result_cid = match job.result_cid {
+            Some(result_cid) => {
+                // the mirror is not persisted, so ask the contract whether the submission
+                // landed before the worker was interrupted, it does not dedupe results
+                let contract_id = &self.config.contract_id;
+                if has_submitted(&self.rpc_client, contract_id, id, is_training, &result_cid)
+                    .await?
+                {
+                    tracing::info!(id, result_cid, "result was submitted already");
+                    return self.queue.set_state(id, JobState::Submitted);
+                }
+                tracing::info!(id, result_cid, "submitting result published earlier");
+                self.queue.set_state(id, JobState::Publishing)?;
+                result_cid
+            }
+            None => {
+                tracing::info!(id, "processing job");
+                let result_cid = match job.kind {
+                    JobKind::Training(request) => self.train(id, request, shutdown).await?,
+                    JobKind::Inference(inference) => self.infer(id, inference, shutdown).await?,
+                };
+                self.queue.set_result(id, &result_cid)?;
+                result_cid
+            }
+        };
+
+        self.ensure_not_cancelled(id, shutdown).await?;
+        let (rpc_client, signer, contract_id) = (
+            &self.rpc_client,
+            &self.config.signer,
+            &self.config.contract_id,
+        );
+        if is_training {
+            complete_request(rpc_client, signer, contract_id, id, &result_cid).await?;
+        } else {
+            complete_inference_request(rpc_client, signer, contract_id, id, &result_cid).await?;
+        }
+        self.queue.set_state(id, JobState::Submitted)
+    }
+
+    /// Stop a job between stages once the indexer saw its request being cancelled or the
+    /// node is shutting down, a computation that already started is not interrupted
+    async fn ensure_not_cancelled(
+        &self,
+        id: JobId,
+        shutdown: &watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        if *shutdown.borrow() {
+            return Err(ShuttingDown.into());
+        }
+        if self.mirror.is_cancelled(id).await {
+            anyhow::bail!("request {id} was cancelled");
+        }
+        Ok(())
+    }
+
+    async fn train(
+        &self,
+        id: JobId,
+        request: TrainingRequest,
+        shutdown: &watch::Receiver<bool>,
+    ) -> anyhow::Result<String> {
+        self.queue.set_state(id, JobState::Fetching)?;
+        tracing::info!(dataset = request.data.dataset, "fetching dataset");
+        let timer = stage_timer("training", "fetching");
+        let dataset = self.cache.get(&request.data.dataset).await?;
+        timer.observe_duration();
+
+        self.ensure_not_cancelled(id, shutdown).await?;
+        self.queue.set_state(id, JobState::Training)?;
+        tracing::info!(epochs = request.epochs, model_type = ?request.model_type, "training model");
+        let options = self.model_options.clone();
+        let timer = stage_timer("training", "training");
+        let path = dataset.path().to_path_buf();
+        let params = tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(&path).context("failed to open dataset")?;
+            model::run_training(
+                &mut file,
+                &request.data.compressed_secret_key,
+                request.epochs,
+                request.model_type,
+                &options,
+            )
+        })
+        .await??;
+        timer.observe_duration();
+        drop(dataset);
+
+        self.ensure_not_cancelled(id, shutdown).await?;
+        self.queue.set_state(id, JobState::Publishing)?;
+        let timer = stage_timer("training", "publishing");
+        let model_cid = self.ipfs.publish_bytes(params.to_bytes()?.into()).await?;
+        timer.observe_duration();
+        tracing::info!(model_cid, "published model parameters");
+
+        let timer = stage_timer("training", "pinning");
+        self.replicator
+            .pin(&model_cid, &format!("veilnet-model-{id}"))
+            .await?;
+        timer.observe_duration();
+        Ok(model_cid)
+    }
+
+    async fn infer(
+        &self,
+        id: JobId,
+        inference: InferenceRequest,
+        shutdown: &watch::Receiver<bool>,
+    ) -> anyhow::Result<String> {
+        self.queue.set_state(id, JobState::Fetching)?;
+        tracing::info!(
+            model = inference.model,
+            input = inference.input.dataset,
+            "fetching model and input"
+        );
+        let timer = stage_timer("inference", "fetching");
+        let model = self.cache.get(&inference.model).await?;
+        let model_params = ModelParams::from_bytes(&tokio::fs::read(model.path()).await?)?;
+        drop(model);
+        let input = self.cache.get(&inference.input.dataset).await?;
+        timer.observe_duration();
+
+        self.ensure_not_cancelled(id, shutdown).await?;
+        self.queue.set_state(id, JobState::Training)?;
+        let options = self.model_options.clone();
+        let timer = stage_timer("inference", "inference");
+        // the encrypted predictions are written next to the input and streamed from there
+        let input_path = input.path().to_path_buf();
+        let predictions_path = input_path.with_extension("predictions");
+        let output_path = predictions_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(&input_path).context("failed to open input")?;
+            let output =
+                std::fs::File::create(&output_path).context("failed to create predictions file")?;
+            let mut output = BufWriter::new(output);
+            model::run_inference(
+                &mut file,
+                &inference.input.compressed_secret_key,
+                &model_params,
+                &mut output,
+                &options,
+            )?;
+            output.flush().context("failed to write predictions")?;
+            anyhow::Ok(())
+        })
+        .await??;
+        timer.observe_duration();
+        drop(input);
+
+        self.ensure_not_cancelled(id, shutdown).await?;
+        self.queue.set_state(id, JobState::Publishing)?;
+        let timer = stage_timer("inference", "publishing");
+        let predictions_cid = self.ipfs.publish_file(&predictions_path).await?;
+        timer.observe_duration();
+        if let Err(err) = tokio::fs::remove_file(&predictions_path).await {
+            tracing::warn!(?err, "failed to remove published predictions");
+        }
+        tracing::info!(predictions_cid, "published predictions");
+
+        let timer = stage_timer("inference", "pinning");
+        self.replicator
+            .pin(&predictions_cid, &format!("veilnet-predictions-{id}"))
+            .await?;
+        timer.observe_duration();
+        Ok(predictions_cid)
+    }
+}
 
-    Ok(result)
+/// Time one stage of a job, only stages that complete are observed
+fn stage_timer(kind: &str, stage: &str) -> prometheus::HistogramTimer {
+    metrics::JOB_STAGE_SECONDS
+        .with_label_values(&[kind, stage])
+        .start_timer()
 }
@@ -0,0 +1,119 @@
+//! Drives the IPFS service over a local blob store.
+use std::path::Path;
+
+use clap::Parser;
+use serde_json::json;
+
+use node::cache::{self, DatasetCache};
+use node::ipfs_service::{self, IpfsService, IpfsServiceHandle, Options};
+use node::storage::{BlobStore, LocalStore, Storage};
+
+const HELLO_CID: &str = "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e";
+
+/// A service over a local store holding "hello world", saving fetched files in `dir/downloads`
+async fn service(dir: &Path, args: &[&str]) -> (IpfsServiceHandle, IpfsService) {
+    let store = Storage::Local(LocalStore::new(&dir.join("blobs")).unwrap());
+    assert_eq!(
+        store.put_bytes("hello world".into()).await.unwrap(),
+        HELLO_CID
+    );
+    let options = Options::parse_from(["node"].iter().chain(args));
+    ipfs_service::run(store, &dir.join("downloads"), &options)
+}
+
+#[tokio::test]
+async fn fetches_files_and_publishes_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let (handle, ipfs) = service(dir.path(), &[]).await;
+
+    let path = ipfs.fetch_file(HELLO_CID, "hello.txt").await.unwrap();
+    assert_eq!(path, dir.path().join("downloads/hello.txt"));
+    assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+
+    let data = json!({ "weights": [1, 2, 3] });
+    let cid = ipfs.publish_json(data.clone()).await.unwrap();
+    let path = ipfs.fetch_file(&cid, "model.json").await.unwrap();
+    let published: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+    assert_eq!(published, data);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn publishes_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let (handle, ipfs) = service(dir.path(), &[]).await;
+    let cid = ipfs.publish_bytes("hello world".into()).await.unwrap();
+    assert_eq!(cid, HELLO_CID);
+    let path = ipfs.fetch_file(&cid, "hello.bin").await.unwrap();
+    assert_eq!(std::fs::read(path).unwrap(), b"hello world");
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn publishes_and_pins_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let (handle, ipfs) = service(dir.path(), &[]).await;
+    let path = dir.path().join("hello.txt");
+    std::fs::write(&path, "hello world").unwrap();
+    let cid = ipfs.publish_file(&path).await.unwrap();
+    assert_eq!(cid, HELLO_CID);
+    ipfs.pin(&cid).await.unwrap();
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_file_names_outside_the_download_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let (handle, ipfs) = service(dir.path(), &[]).await;
+    for filename in ["../escaped", "nested/file", "", ".."] {
+        assert!(ipfs.fetch_file(HELLO_CID, filename).await.is_err());
+    }
+    assert!(!dir.path().join("escaped").exists());
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn answers_more_requests_than_it_has_queue_and_transfer_slots() {
+    let dir = tempfile::tempdir().unwrap();
+    let args = [
+        "--ipfs-queue-size",
+        "1",
+        "--ipfs-max-concurrent-transfers",
+        "2",
+    ];
+    let (handle, ipfs) = service(dir.path(), &args).await;
+    let fetches = (0..16).map(|i| {
+        let ipfs = ipfs.clone();
+        async move { ipfs.fetch_file(HELLO_CID, &format!("hello-{i}")).await }
+    });
+    for path in futures::future::join_all(fetches).await {
+        assert_eq!(std::fs::read(path.unwrap()).unwrap(), b"hello world");
+    }
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn refuses_requests_after_shutting_down() {
+    let dir = tempfile::tempdir().unwrap();
+    let (handle, ipfs) = service(dir.path(), &[]).await;
+    handle.shutdown().await.unwrap();
+    assert!(ipfs.fetch_file(HELLO_CID, "hello.txt").await.is_err());
+}
+
+#[tokio::test]
+async fn the_dataset_cache_fetches_through_the_service() {
+    let dir = tempfile::tempdir().unwrap();
+    let (handle, ipfs) = service(dir.path(), &[]).await;
+    let downloads = dir.path().join("downloads");
+    let options =
+        cache::Options::parse_from(["node", "--download-dir", downloads.to_str().unwrap()]);
+    let cache = DatasetCache::new(ipfs, &options);
+
+    let file = cache.get(HELLO_CID).await.unwrap();
+    assert_eq!(file.path(), downloads.join(HELLO_CID));
+    assert_eq!(std::fs::read(file.path()).unwrap(), b"hello world");
+    drop(file);
+    handle.shutdown().await.unwrap();
+}
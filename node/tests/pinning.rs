@@ -0,0 +1,151 @@
+//! Pins results at a mock pinning service before they are submitted.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use clap::Parser;
+use serde_json::{json, Value};
+
+use node::ipfs_service;
+use node::pinning::{Options, PinningService, Replicator};
+use node::storage::{BlobStore, LocalStore, Storage};
+
+const TOKEN: &str = "secret";
+
+/// Polls of pin requests so far
+#[derive(Clone, Default)]
+struct Polls(Arc<AtomicUsize>);
+
+/// A pinning service that pins after two polls, fails pins named `fail` and never answers
+/// pins named `hang`
+async fn pinning_service() -> (reqwest::Url, Polls) {
+    async fn add(headers: HeaderMap, Json(pin): Json<Value>) -> Result<Json<Value>, StatusCode> {
+        authorize(&headers)?;
+        if pin["name"] == "hang" {
+            std::future::pending::<()>().await;
+        }
+        let requestid = if pin["name"] == "fail" { "fail" } else { "ok" };
+        Ok(Json(json!({ "requestid": requestid, "status": "queued" })))
+    }
+
+    async fn status(
+        State(polls): State<Polls>,
+        headers: HeaderMap,
+        Path(requestid): Path<String>,
+    ) -> Result<Json<Value>, StatusCode> {
+        authorize(&headers)?;
+        let poll = polls.0.fetch_add(1, Ordering::SeqCst);
+        let status = match (requestid.as_str(), poll) {
+            ("fail", _) => "failed",
+            (_, 0) => "pinning",
+            _ => "pinned",
+        };
+        Ok(Json(json!({ "requestid": requestid, "status": status })))
+    }
+
+    fn authorize(headers: &HeaderMap) -> Result<(), StatusCode> {
+        match headers.get("authorization") {
+            Some(value) if value == &format!("Bearer {TOKEN}") => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    let polls = Polls::default();
+    let router = Router::new()
+        .route("/psa/pins", post(add))
+        .route("/psa/pins/:requestid", get(status))
+        .with_state(polls.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    (format!("http://{addr}/psa").parse().unwrap(), polls)
+}
+
+fn options(args: &[&str]) -> Options {
+    Options::parse_from(["node", "--pin-poll-interval-ms", "10"].iter().chain(args))
+}
+
+fn service(url: &reqwest::Url, args: &[&str]) -> PinningService {
+    let options = options(&[&["--pinning-service-url", url.as_str()], args].concat());
+    PinningService::new(url, &options).unwrap()
+}
+
+#[tokio::test]
+async fn waits_until_the_service_pinned() {
+    let (url, polls) = pinning_service().await;
+    let pinning = service(&url, &["--pinning-service-token", TOKEN]);
+    pinning
+        .pin("bafkreiexample", "veilnet-model-1")
+        .await
+        .unwrap();
+    assert_eq!(polls.0.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn fails_when_the_service_fails_or_rejects_the_token() {
+    let (url, _) = pinning_service().await;
+    let pinning = service(&url, &["--pinning-service-token", TOKEN]);
+    assert!(pinning.pin("bafkreiexample", "fail").await.is_err());
+
+    let pinning = service(&url, &["--pinning-service-token", "wrong"]);
+    let err = pinning
+        .pin("bafkreiexample", "veilnet-model-1")
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("401"), "{err:#}");
+}
+
+#[tokio::test]
+async fn gives_up_after_the_timeout() {
+    let (url, _) = pinning_service().await;
+    let pinning = service(
+        &url,
+        &["--pinning-service-token", TOKEN, "--pin-timeout", "0"],
+    );
+    assert!(pinning
+        .pin("bafkreiexample", "veilnet-model-1")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn gives_up_on_a_service_that_does_not_answer() {
+    let (url, _) = pinning_service().await;
+    for timeout in ["--pin-timeout", "--pinning-service-timeout"] {
+        let pinning = service(&url, &["--pinning-service-token", TOKEN, timeout, "1"]);
+        let start = Instant::now();
+        assert!(pinning.pin("bafkreiexample", "hang").await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5), "{timeout}");
+    }
+}
+
+#[tokio::test]
+async fn pins_without_fetching_the_result_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = dir.path().join("blobs");
+    let store = Storage::Local(LocalStore::new(&blobs).unwrap());
+    let downloads = dir.path().join("downloads");
+    let ipfs_options = ipfs_service::Options::parse_from(["node"]);
+    let (handle, ipfs) = ipfs_service::run(store.clone(), &downloads, &ipfs_options);
+    let replicator = Replicator::new(ipfs, &store, &options(&[])).unwrap();
+
+    let cid = store.put_bytes("hello world".into()).await.unwrap();
+    replicator.pin(&cid, "veilnet-model-1").await.unwrap();
+    assert!(!downloads.exists() || std::fs::read_dir(&downloads).unwrap().count() == 0);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn remote_pinning_needs_the_ipfs_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = Storage::Local(LocalStore::new(dir.path()).unwrap());
+    let ipfs_options = ipfs_service::Options::parse_from(["node"]);
+    let (handle, ipfs) = ipfs_service::run(store.clone(), dir.path(), &ipfs_options);
+    let options = options(&["--pinning-service-url", "http://localhost:1/psa"]);
+    assert!(Replicator::new(ipfs, &store, &options).is_err());
+    handle.shutdown().await.unwrap();
+}
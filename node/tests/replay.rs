@@ -0,0 +1,143 @@
+//! Replays recorded blocks from `tests/fixtures` through the indexer.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use near_lake_primitives::AccountId;
+use node::block_source::{BlockSource, DirectoryBlockSource};
+use node::indexer::{self, Checkpoint, Indexer, Options, SkipReason};
+use node::mirror::ContractMirror;
+use node::model::ModelType;
+use node::queue::{JobKind, JobState, RequestQueue};
+
+fn blocks_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/blocks")
+}
+
+/// Blocks with actions the indexer has to skip, see [`skipped_actions_are_counted_by_reason`]
+fn skipped_blocks_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/skipped_blocks")
+}
+
+fn contract_id() -> AccountId {
+    "veilnet.test.near".parse().unwrap()
+}
+
+fn worker_id() -> AccountId {
+    "worker.test.near".parse().unwrap()
+}
+
+#[tokio::test]
+async fn directory_source_replays_from_start_in_height_order() {
+    let mut source = DirectoryBlockSource::new(&blocks_dir(), 0).unwrap();
+    let mut heights = Vec::new();
+    while let Some(message) = source.next_block().await.unwrap() {
+        heights.push(message.block.header.height);
+    }
+    assert_eq!(heights, vec![100, 101, 102, 103]);
+
+    let mut source = DirectoryBlockSource::new(&blocks_dir(), 103).unwrap();
+    let message = source.next_block().await.unwrap().unwrap();
+    assert_eq!(message.block.header.height, 103);
+    assert!(source.next_block().await.unwrap().is_none());
+}
+
+/// Replay every recorded block into a fresh store
+async fn replay_all() -> (sled::Db, RequestQueue) {
+    let (db, queue, _) = replay_dir(&blocks_dir()).await;
+    (db, queue)
+}
+
+async fn replay_dir(dir: &Path) -> (sled::Db, RequestQueue, Indexer) {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let queue = RequestQueue::open(&db).unwrap();
+    let mirror = ContractMirror::default();
+    let options = Options::try_parse_from(["indexer"]).unwrap();
+
+    let source = DirectoryBlockSource::new(dir, 0).unwrap();
+    let indexer = indexer::replay(
+        source,
+        &options,
+        &contract_id(),
+        &worker_id(),
+        &queue,
+        &mirror,
+        &db,
+    )
+    .await
+    .unwrap();
+    (db, queue, indexer)
+}
+
+#[tokio::test]
+async fn replay_checkpoints_every_block() {
+    let (db, _) = replay_all().await;
+
+    let checkpoint = Checkpoint::open(&db).unwrap();
+    assert_eq!(checkpoint.load().unwrap(), Some(103));
+    assert_eq!(checkpoint.start_block_height(0).unwrap(), 104);
+}
+
+#[tokio::test]
+async fn succeeded_add_request_is_queued() {
+    let (_, queue) = replay_all().await;
+
+    // block 100, `add_request` returned the request id
+    let job = queue.get(7).unwrap().expect("request 7 is queued");
+    assert_eq!(job.state, JobState::Queued);
+    let JobKind::Training(request) = job.kind else {
+        panic!("request 7 is a training request");
+    };
+    assert_eq!(request.epochs, 2);
+    assert_eq!(request.model_type, ModelType::Perceptron);
+    assert_eq!(request.creator.as_str(), "client.test.near");
+    assert_eq!(request.workers, vec![worker_id()]);
+}
+
+#[tokio::test]
+async fn failed_add_request_is_not_queued() {
+    let (_, queue) = replay_all().await;
+
+    // block 102, the fee check failed even though the receipt logged the event
+    assert!(queue.get(8).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn add_request_continued_in_another_receipt_is_queued() {
+    let (_, queue) = replay_all().await;
+
+    // block 103, `add_request` returned a promise, its own receipt still succeeded
+    assert!(queue.get(9).unwrap().is_some());
+    assert_eq!(queue.len_queued().unwrap(), 2);
+}
+
+#[tokio::test]
+async fn recorded_blocks_skip_nothing() {
+    let (_, _, indexer) = replay_dir(&blocks_dir()).await;
+    assert!(indexer.skipped_actions().await.is_empty());
+}
+
+#[tokio::test]
+async fn skipped_actions_are_counted_by_reason() {
+    let (db, queue, indexer) = replay_dir(&skipped_blocks_dir()).await;
+
+    // block 200 calls `add_request` with malformed arguments, with an invalid worker id and
+    // without logging the event, and two receipts that are not executed in that block
+    let skipped = indexer.skipped_actions().await;
+    let expected = HashMap::from([
+        (SkipReason::MalformedArguments, 1),
+        (SkipReason::InvalidAccountId, 1),
+        (SkipReason::MissingEvent, 1),
+        // block 209 is the last of the ten blocks the missing receipt is looked for in
+        (SkipReason::MissingReceipt, 1),
+    ]);
+    assert_eq!(skipped, expected);
+
+    // the deferred receipt is executed in block 201, its request is queued after all
+    assert_eq!(queue.len_queued().unwrap(), 1);
+    assert!(queue.get(13).unwrap().is_some());
+    for skipped in [10, 11] {
+        assert!(queue.get(skipped).unwrap().is_none());
+    }
+    assert_eq!(Checkpoint::open(&db).unwrap().load().unwrap(), Some(209));
+}
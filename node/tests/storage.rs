@@ -0,0 +1,116 @@
+//! Round trips through the blob stores that do not need an IPFS node.
+use std::path::Path;
+
+use clap::Parser;
+use node::cid::CidError;
+use node::storage::{BlobStore, LocalStore, Options, Storage};
+
+const HELLO: &[u8] = b"hello world";
+/// CIDv1 of `HELLO` as a single raw block
+const HELLO_CID: &str = "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e";
+
+#[tokio::test]
+async fn local_store_round_trips_bytes_and_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = LocalStore::new(&dir.path().join("blobs")).unwrap();
+    assert!(store.is_reachable().await);
+
+    let cid = store.put_bytes(HELLO.into()).await.unwrap();
+    assert_eq!(cid, HELLO_CID);
+
+    let source = dir.path().join("source");
+    std::fs::write(&source, HELLO).unwrap();
+    assert_eq!(store.put_file(&source).await.unwrap(), HELLO_CID);
+
+    let dest = dir.path().join("fetched");
+    store.get(&cid, &dest).await.unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), HELLO);
+}
+
+#[tokio::test]
+async fn local_store_rejects_corrupted_blobs() {
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = dir.path().join("blobs");
+    let store = LocalStore::new(&blobs).unwrap();
+    let cid = store.put_bytes(HELLO.into()).await.unwrap();
+    std::fs::write(blobs.join(&cid), b"goodbye world").unwrap();
+
+    let dest = dir.path().join("fetched");
+    let err = store.get(&cid, &dest).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<CidError>(),
+        Some(CidError::HashMismatch { .. })
+    ));
+    assert!(!dest.exists());
+    assert_no_partial_files(dir.path());
+}
+
+#[tokio::test]
+async fn local_store_misses_unknown_blobs() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = LocalStore::new(dir.path()).unwrap();
+    let dest = dir.path().join("fetched");
+    assert!(store.get(HELLO_CID, &dest).await.is_err());
+    assert!(store.get("../etc/passwd", &dest).await.is_err());
+    assert!(!dest.exists());
+}
+
+#[tokio::test]
+async fn storage_selects_the_configured_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = Options::parse_from([
+        "node",
+        "--blob-store",
+        "local",
+        "--local-store-dir",
+        dir.path().to_str().unwrap(),
+    ]);
+    let ipfs_options = node::ipfs::Options::parse_from(["node"]);
+    let store = Storage::new(&options, &ipfs_options).unwrap();
+    assert!(matches!(store, Storage::Local(_)));
+    assert_eq!(store.put_bytes(HELLO.into()).await.unwrap(), HELLO_CID);
+    assert!(dir.path().join(HELLO_CID).exists());
+}
+
+/// Runs against an S3-compatible store such as a local MinIO:
+/// `S3_TEST_ENDPOINT=http://localhost:9000 S3_TEST_BUCKET=veilnet S3_TEST_ACCESS_KEY_ID=minioadmin
+/// S3_TEST_SECRET_ACCESS_KEY=minioadmin cargo test --test storage -- --ignored`
+#[tokio::test]
+#[ignore = "needs an S3-compatible store"]
+async fn s3_store_round_trips_bytes_and_files() {
+    let env = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{name} is not set"));
+    let options = Options::parse_from([
+        "node".to_string(),
+        "--blob-store=s3".to_string(),
+        format!("--s3-endpoint={}", env("S3_TEST_ENDPOINT")),
+        format!("--s3-bucket={}", env("S3_TEST_BUCKET")),
+        format!("--s3-access-key-id={}", env("S3_TEST_ACCESS_KEY_ID")),
+        format!(
+            "--s3-secret-access-key={}",
+            env("S3_TEST_SECRET_ACCESS_KEY")
+        ),
+        "--s3-prefix=tests/".to_string(),
+    ]);
+    let ipfs_options = node::ipfs::Options::parse_from(["node"]);
+    let store = Storage::new(&options, &ipfs_options).unwrap();
+    assert!(store.is_reachable().await);
+
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(store.put_bytes(HELLO.into()).await.unwrap(), HELLO_CID);
+    let source = dir.path().join("source");
+    std::fs::write(&source, HELLO).unwrap();
+    assert_eq!(store.put_file(&source).await.unwrap(), HELLO_CID);
+
+    let dest = dir.path().join("fetched");
+    store.get(HELLO_CID, &dest).await.unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), HELLO);
+}
+
+fn assert_no_partial_files(dir: &Path) {
+    let partial = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().ends_with(".partial"))
+        .count();
+    assert_eq!(partial, 0);
+}
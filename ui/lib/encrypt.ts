@@ -1,25 +1,43 @@
 // TfheWebEncryptionService.ts
 import {
+    FheUint8,
     ShortintParametersName,
     ShortintParameters,
     TfheClientKey,
     TfheCompressedServerKey,
     TfheConfigBuilder as ConfigBuilder,
-    Shortint,
 } from 'tfhe';
 
+// Dataset container read by the node, see `node/src/dataset.rs` for the layout
+const DATASET_MAGIC = new TextEncoder().encode('VNFL');
+const DATASET_FORMAT_VERSION = 1;
+// Upper bound the node accepts for a serialized ciphertext or server key
+const SERIALIZED_SIZE_LIMIT = BigInt(1) << BigInt(30);
+// Largest value of a FheUint8 column
+const MAX_VALUE = 255;
+
 export interface EncryptionKeys {
     clientKey: TfheClientKey;
     serverKey: TfheCompressedServerKey;
 }
 
 export interface EncryptedData {
-    encryptedValues: Uint8Array[][]; // rows and columns
+    dataset: Uint8Array; // the encrypted dataset container uploaded to IPFS
     originalHeaders: string[];
     rowCount: number;
     columnCount: number;
 }
 
+/**
+ * Header of the dataset container, mirrors `DatasetHeader` in `node/src/dataset.rs`
+ */
+interface DatasetHeader {
+    parameters: 'Message2Carry2KsPbs';
+    columns: { name: string; column_type: 'FheUint8' }[];
+    row_count: number;
+    label_column: number | null; // null for inference inputs
+}
+
 export interface CsvRow {
     [key: string]: string | number;
 }
@@ -44,10 +62,11 @@ export class TfheWebEncryptionService {
      */
     public initializeTfhe(): EncryptionKeys {
         try {
-            // Using small message parameters for better performance
+            // The node deserializes ciphertexts and keys under this parameter set
             const params = new ShortintParameters(ShortintParametersName.PARAM_MESSAGE_2_CARRY_2_KS_PBS);
 
             const config = ConfigBuilder.default()
+                .use_custom_parameters(params)
                 .build();
 
             const clientKey = TfheClientKey.generate(config);
@@ -76,13 +95,13 @@ export class TfheWebEncryptionService {
             headers.map(header => {
                 const value = row[header];
                 if (typeof value === 'number') {
-                    if (value < 0 || value > 7) { // Adjust range based on PARAM_SMALL_MESSAGE_2_CARRY_2
-                        throw new Error(`Value ${value} in column ${header} is out of range (0-7)`);
+                    if (value < 0 || value > MAX_VALUE) {
+                        throw new Error(`Value ${value} in column ${header} is out of range (0-${MAX_VALUE})`);
                     }
-                    return value;
+                    return Math.floor(value);
                 }
                 const numValue = parseFloat(value as string);
-                if (isNaN(numValue) || numValue < 0 || numValue > 7) {
+                if (isNaN(numValue) || numValue < 0 || numValue > MAX_VALUE) {
                     throw new Error(`Invalid or out of range value in column ${header}`);
                 }
                 return Math.floor(numValue);
@@ -93,31 +112,41 @@ export class TfheWebEncryptionService {
     }
 
     /**
-     * Encrypt CSV data
+     * Encrypt CSV data into the dataset container the node reads.
+     * `labelColumn` names the column training predicts, leave it out for inference inputs.
      */
-    public async encryptCsvData(data: CsvRow[]): Promise<EncryptedData> {
+    public async encryptCsvData(data: CsvRow[], labelColumn?: string): Promise<EncryptedData> {
         try {
             if (!this.keys) {
                 this.initializeTfhe();
             }
 
             const { numericData, headers } = this.convertToNumeric(data);
-            // a double array is actually a triple array
-            const encryptedValues: Uint8Array[][] = [];
+            const labelIndex = labelColumn === undefined ? null : headers.indexOf(labelColumn);
+            if (labelIndex === -1) {
+                throw new Error(`Label column ${labelColumn} not found`);
+            }
 
-            // Encrypt each value individually
+            const header: DatasetHeader = {
+                parameters: 'Message2Carry2KsPbs',
+                columns: headers.map(name => ({ name, column_type: 'FheUint8' })),
+                row_count: numericData.length,
+                label_column: labelIndex,
+            };
+            const chunks: Uint8Array[] = [datasetPreamble(header)];
+
+            // Every cell is its length followed by the serialized ciphertext, row by row
             for (const row of numericData) {
-                const rowBuffer: Uint8Array[] = [];
                 for (const value of row) {
-                    const encrypted = Shortint.encrypt(this.keys!.clientKey, BigInt(value));
-                    const serialized = Shortint.serialize_ciphertext(encrypted);
-                    rowBuffer.push(serialized);
+                    const encrypted = FheUint8.encrypt_with_client_key(value, this.keys!.clientKey);
+                    const serialized = encrypted.safe_serialize(SERIALIZED_SIZE_LIMIT);
+                    encrypted.free();
+                    chunks.push(u32(serialized.length), serialized);
                 }
-                encryptedValues.push(rowBuffer);
             }
 
             return {
-                encryptedValues,
+                dataset: concat(chunks),
                 originalHeaders: headers,
                 rowCount: data.length,
                 columnCount: headers.length
@@ -135,8 +164,12 @@ export class TfheWebEncryptionService {
             if (!this.keys) {
                 throw new Error('Keys not initialized');
             }
-            let deserialize = Shortint.deserialize_ciphertext(encryptedValue);
-            return Shortint.decrypt(this.keys.clientKey, deserialize)
+            const ciphertext = FheUint8.safe_deserialize(encryptedValue, SERIALIZED_SIZE_LIMIT);
+            try {
+                return BigInt(ciphertext.decrypt(this.keys.clientKey));
+            } finally {
+                ciphertext.free();
+            }
         } catch (error) {
             throw new Error(`Decryption failed: ${error}`);
         }
@@ -160,7 +193,7 @@ export class TfheWebEncryptionService {
             return Object.keys(row).length === headers.length &&
                 Object.values(row).every(value => {
                     const numValue = typeof value === 'number' ? value : parseFloat(value as string);
-                    return !isNaN(numValue) && numValue >= 0 && numValue <= 7;
+                    return !isNaN(numValue) && numValue >= 0 && numValue <= MAX_VALUE;
                 });
         });
     }
@@ -177,6 +210,33 @@ export class TfheWebEncryptionService {
         if (!this.keys) {
             throw new Error('Keys not initialized');
         }
-        return this.keys.serverKey.serialize();
+        // the node loads it with `safe_deserialize_conformant`
+        return this.keys.serverKey.safe_serialize(SERIALIZED_SIZE_LIMIT);
     }
-}
\ No newline at end of file
+}
+
+/**
+ * Magic, format version, header length and the JSON header of a dataset, integers are little endian
+ */
+function datasetPreamble(header: DatasetHeader): Uint8Array {
+    const encoded = new TextEncoder().encode(JSON.stringify(header));
+    const version = new Uint8Array(2);
+    new DataView(version.buffer).setUint16(0, DATASET_FORMAT_VERSION, true);
+    return concat([DATASET_MAGIC, version, u32(encoded.length), encoded]);
+}
+
+function u32(value: number): Uint8Array {
+    const bytes = new Uint8Array(4);
+    new DataView(bytes.buffer).setUint32(0, value, true);
+    return bytes;
+}
+
+function concat(chunks: Uint8Array[]): Uint8Array {
+    const result = new Uint8Array(chunks.reduce((len, chunk) => len + chunk.length, 0));
+    let offset = 0;
+    for (const chunk of chunks) {
+        result.set(chunk, offset);
+        offset += chunk.length;
+    }
+    return result;
+}